@@ -0,0 +1,42 @@
+// Generated by `icfp2006-rust translate`.
+
+use std::process;
+
+use icfp2006_rust::{Error, UM};
+
+static IMAGE: [u32; 8] = [
+    0xd2000006, 0x30000009, 0xd400003c, 0x30000002, 0xd600000a, 0xa0000000, 0xa0000003, 0x70000000,
+];
+
+#[allow(clippy::all, unreachable_code)]
+fn blocks(finger: usize, r: &mut [u32; 8], budget: u64) -> usize {
+    match finger {
+        0 => {
+            if budget < 5 { return 0; }
+            r[1] = 0x6;
+            r[0] = r[1].wrapping_add(r[1]);
+            r[2] = 0x3c;
+            r[0] = r[0].wrapping_add(r[2]);
+            r[3] = 0xa;
+            5
+        }
+        _ => 0,
+    }
+}
+
+fn main() {
+    let mut um = UM::new(IMAGE.to_vec());
+    um.attach_blocks(blocks);
+
+    match um.spin_cycle() {
+        Ok(()) => {}
+        Err(e @ Error::Fault(_)) => {
+            eprintln!("\nUM: {}", e);
+            process::exit(2);
+        }
+        Err(e) => {
+            eprintln!("\nUM: {}", e);
+            process::exit(1);
+        }
+    }
+}
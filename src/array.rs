@@ -0,0 +1,87 @@
+//! Copy-on-write storage for the machine's arrays.
+
+use std::sync::Arc;
+
+use crate::code::{decode_all, Op};
+
+/// An active array. Cloning is O(1): the platters are shared until one of the
+/// copies is amended. The decoded form is built the first time the array is
+/// loaded as a program and then travels with the platters.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Array {
+    platters: Arc<Vec<u32>>,
+    code: Option<Arc<Vec<Op>>>,
+}
+
+impl Array {
+    pub fn new(platters: Vec<u32>) -> Self {
+        Array { platters: Arc::new(platters), code: None }
+    }
+
+    #[inline(always)]
+    pub fn platters(&self) -> &[u32] {
+        &self.platters
+    }
+
+    /// The decoded platters, shared with this array.
+    pub fn code(&mut self) -> Arc<Vec<Op>> {
+        let platters = &self.platters;
+        self.code.get_or_insert_with(|| Arc::new(decode_all(platters))).clone()
+    }
+
+    /// A copy sharing the platters but not the decoded form, for array 0
+    /// whose decoded form the machine keeps separately.
+    pub fn share_platters(&self) -> Array {
+        Array { platters: self.platters.clone(), code: None }
+    }
+
+    /// Whether `other` has the same platters, comparing storage before
+    /// contents.
+    pub fn same_platters(&self, other: &Array) -> bool {
+        Arc::ptr_eq(&self.platters, &other.platters) || self.platters == other.platters
+    }
+
+    /// Writes a platter, copying the storage first if it is shared.
+    #[inline(always)]
+    pub fn amend(&mut self, offset: usize, value: u32) -> Option<()> {
+        *Arc::make_mut(&mut self.platters).get_mut(offset)? = value;
+        if let Some(code) = &mut self.code {
+            Arc::make_mut(code)[offset] = Op::decode(value);
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_on_write() {
+        let mut a = Array::new(vec![1, 2, 3]);
+        let mut b = a.clone();
+        assert!(Arc::ptr_eq(&a.platters, &b.platters));
+
+        b.amend(0, 9).unwrap();
+        assert_eq!(&[1, 2, 3], a.platters());
+        assert_eq!(&[9, 2, 3], b.platters());
+        assert_eq!(None, a.amend(3, 0));
+    }
+
+    #[test]
+    fn test_same_platters() {
+        let a = Array::new(vec![1, 2, 3]);
+        assert!(a.same_platters(&a.share_platters()));
+        assert!(a.same_platters(&Array::new(vec![1, 2, 3])));
+        assert!(!a.same_platters(&Array::new(vec![1, 2])));
+    }
+
+    #[test]
+    fn test_code_follows_amendments() {
+        let mut a = Array::new(vec![0, 0]);
+        let code = a.code();
+        a.amend(1, 0x7000_0000).unwrap();
+        assert_eq!(0, code[1].code);
+        assert_eq!(7, a.code()[1].code);
+    }
+}
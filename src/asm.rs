@@ -0,0 +1,679 @@
+//! Assembler for a textual UM language.
+//!
+//! ```text
+//! ; comments run from a semicolon to the end of the line
+//! .equ NEWLINE, 10
+//! .macro print reg, char
+//!         ortho \reg, \char
+//!         out \reg
+//! .endm
+//!
+//! start:  print r1, 'H'
+//!         print r1, NEWLINE
+//!         halt
+//! table:  .word start, table + 1, -1
+//! text:   .string "hi\n"
+//!         .zero 4
+//! ```
+//!
+//! Every operator is written with the mnemonic `disasm` prints: `cmov`,
+//! `index`, `amend`, `add`, `mul`, `div`, `nand` take registers A, B and C;
+//! `alloc` and `load` take B and C; `free`, `out` and `in` take C; `halt`
+//! takes nothing and `ortho` takes a register and a value below 2^25.
+//!
+//! Pseudo-instructions expand to several operators (see `pseudo`):
+//! `li r, value` loads any 32-bit value; `not`, `and`, `or` and `sub` take a
+//! destination and sources; `jmp` and `call` take an address or a register;
+//! `ret` returns to the address `call` left in the link register.
+//! `.temps ra, rb` and `.link r` choose the registers expansions may use,
+//! r6, r7 and r5 by default.
+//!
+//! Expressions combine numbers (decimal, `0x`, `0b`, `'c'`), labels, `.equ`
+//! symbols and `$` (the current address) with `| ^ & << >> + - * / % ~` and
+//! parentheses. Macro bodies refer to their parameters as `\name`, and `\@`
+//! expands to a number unique to each expansion, for local labels.
+
+mod expr;
+mod lexer;
+pub mod pseudo;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::instruction::{Instruction, MNEMONICS};
+use expr::Expr;
+use lexer::{Spanned, Token};
+use pseudo::Conventions;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    /// 1-based source line; for code expanded from a macro, the line of the
+    /// outermost invocation.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+const MAX_MACRO_DEPTH: usize = 64;
+
+/// A source line after comment removal and macro expansion.
+struct Line {
+    number: usize,
+    text: String,
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+}
+
+#[derive(Debug)]
+enum Operand {
+    Register(u8),
+    Expr(Expr),
+}
+
+const PSEUDO: [&str; 8] = ["li", "not", "and", "or", "sub", "jmp", "call", "ret"];
+
+#[derive(Debug)]
+struct Pseudo {
+    name: String,
+    operands: Vec<Operand>,
+    conventions: Conventions,
+    /// Whether a value was unknown in the first pass, so the fixed-length
+    /// expansion was sized for.
+    long: bool,
+    len: u32,
+}
+
+#[derive(Debug)]
+enum Item {
+    Op(u8, Vec<Operand>),
+    Pseudo(Pseudo),
+    Words(Vec<Expr>),
+    Bytes(Vec<u8>),
+    Zero(u32),
+}
+
+struct Statement {
+    line: usize,
+    addr: u32,
+    item: Item,
+}
+
+impl Item {
+    fn len(&self) -> u32 {
+        match self {
+            Item::Op(..) => 1,
+            Item::Pseudo(p) => p.len,
+            Item::Words(words) => words.len() as u32,
+            Item::Bytes(bytes) => bytes.len() as u32,
+            Item::Zero(n) => *n,
+        }
+    }
+}
+
+enum Symbol {
+    Label(u32),
+    Const { expr: Expr, line: usize, here: u32 },
+}
+
+/// Splits tokens at top-level commas.
+fn split_operands(tokens: &[Spanned]) -> Vec<&[Spanned]> {
+    if tokens.is_empty() {
+        return vec![];
+    }
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, t) in tokens.iter().enumerate() {
+        match t.token {
+            Token::Punct("(") => depth += 1,
+            Token::Punct(")") => depth -= 1,
+            Token::Punct(",") if depth == 0 => {
+                parts.push(&tokens[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&tokens[start..]);
+    parts
+}
+
+/// Number of leading tokens that are `label:` pairs.
+fn label_tokens(tokens: &[Spanned]) -> usize {
+    let mut i = 0;
+    while let (Some(Token::Ident(_)), Some(Token::Punct(":"))) =
+        (tokens.get(i).map(|t| &t.token), tokens.get(i + 1).map(|t| &t.token))
+    {
+        i += 2;
+    }
+    i
+}
+
+fn plain(tokens: &[Spanned]) -> Vec<Token> {
+    tokens.iter().map(|t| t.token.clone()).collect()
+}
+
+struct Assembler {
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    symbols: HashMap<String, Symbol>,
+    conventions: Conventions,
+}
+
+impl Assembler {
+    fn new() -> Self {
+        Assembler {
+            macros: HashMap::new(),
+            expansions: 0,
+            symbols: HashMap::new(),
+            conventions: Conventions::default(),
+        }
+    }
+
+    /// Collects macro definitions and expands invocations.
+    fn expand(&mut self, source: &str) -> Result<Vec<Line>, AsmError> {
+        let mut lines = vec![];
+        let mut defining: Option<(String, Macro, usize)> = None;
+
+        for (i, raw) in source.lines().enumerate() {
+            let number = i + 1;
+            let err = |message: String| AsmError { line: number, message };
+            let text = lexer::strip_comment(raw);
+            let head = text.split_whitespace().next().unwrap_or("");
+
+            if let Some((name, mut m, start)) = defining.take() {
+                if head == ".endm" {
+                    self.macros.insert(name, m);
+                } else if head == ".macro" {
+                    return Err(err("nested .macro definition".to_string()));
+                } else {
+                    m.body.push(text.to_string());
+                    defining = Some((name, m, start));
+                }
+                continue;
+            }
+
+            match head {
+                ".macro" => {
+                    let rest = text.trim_start()[".macro".len()..].replace(',', " ");
+                    let mut words = rest.split_whitespace().map(str::to_string);
+                    let name = words.next().ok_or_else(|| err(".macro needs a name".to_string()))?;
+                    let params = words.collect();
+                    defining = Some((name, Macro { params, body: vec![] }, number));
+                }
+                ".endm" => return Err(err(".endm without .macro".to_string())),
+                _ => self.expand_line(number, text, 0, &mut lines)?,
+            }
+        }
+
+        match defining {
+            Some((name, _, start)) => Err(AsmError {
+                line: start,
+                message: format!("macro {} has no .endm", name),
+            }),
+            None => Ok(lines),
+        }
+    }
+
+    fn expand_line(&mut self, number: usize, text: &str, depth: usize, out: &mut Vec<Line>) -> Result<(), AsmError> {
+        let err = |message: String| AsmError { line: number, message };
+        let tokens = lexer::tokenize(text).map_err(err)?;
+        let labels = label_tokens(&tokens);
+        let name = match tokens.get(labels).map(|t| &t.token) {
+            Some(Token::Ident(name)) if self.macros.contains_key(name) => name.clone(),
+            _ => {
+                out.push(Line { number, text: text.to_string() });
+                return Ok(());
+            }
+        };
+        if depth == MAX_MACRO_DEPTH {
+            return Err(err(format!("macro {} nested too deeply", name)));
+        }
+
+        if labels > 0 {
+            let end = tokens[labels - 1].end;
+            out.push(Line { number, text: text[..end].to_string() });
+        }
+        let args: Vec<&str> = split_operands(&tokens[labels + 1..])
+            .into_iter()
+            .map(|arg| match (arg.first(), arg.last()) {
+                (Some(first), Some(last)) => &text[first.start..last.end],
+                _ => "",
+            })
+            .collect();
+
+        let m = &self.macros[&name];
+        if args.len() != m.params.len() {
+            return Err(err(format!(
+                "macro {} takes {} arguments, got {}",
+                name,
+                m.params.len(),
+                args.len()
+            )));
+        }
+
+        // Longer names first, so `\ab` is not clobbered by `\a`.
+        let mut params: Vec<(&String, &str)> = m.params.iter().zip(args).collect();
+        params.sort_by_key(|(p, _)| std::cmp::Reverse(p.len()));
+        self.expansions += 1;
+        let unique = self.expansions.to_string();
+        let body: Vec<String> = m
+            .body
+            .iter()
+            .map(|line| {
+                let mut line = line.replace("\\@", &unique);
+                for (param, arg) in &params {
+                    line = line.replace(&format!("\\{}", param), arg);
+                }
+                line
+            })
+            .collect();
+
+        for line in body {
+            self.expand_line(number, &line, depth + 1, out)?;
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str, symbol: Symbol, line: usize) -> Result<(), AsmError> {
+        if self.symbols.contains_key(name) {
+            return Err(AsmError { line, message: format!("{} is already defined", name) });
+        }
+        self.symbols.insert(name.to_string(), symbol);
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str, depth: usize) -> Result<i64, String> {
+        match self.symbols.get(name) {
+            Some(Symbol::Label(addr)) => Ok(*addr as i64),
+            Some(Symbol::Const { .. }) if depth == MAX_MACRO_DEPTH => {
+                Err(format!("{} is defined in terms of itself", name))
+            }
+            Some(Symbol::Const { expr, here, .. }) => {
+                expr.eval(*here, &mut |n| self.lookup(n, depth + 1))
+            }
+            None => Err(format!("undefined symbol {}", name)),
+        }
+    }
+
+    fn eval(&self, expr: &Expr, here: u32) -> Result<i64, String> {
+        expr.eval(here, &mut |n| self.lookup(n, 0))
+    }
+
+    /// Evaluates to a platter; negative values are taken as two's complement.
+    fn word(&self, expr: &Expr, here: u32) -> Result<u32, String> {
+        let v = self.eval(expr, here)?;
+        if (-(1 << 31)..1 << 32).contains(&v) {
+            Ok(v as u32)
+        } else {
+            Err(format!("{} does not fit in 32 bits", v))
+        }
+    }
+
+    fn parse_operand(tokens: &[Spanned]) -> Result<Operand, String> {
+        if let [Spanned { token: Token::Ident(name), .. }] = tokens {
+            let b = name.as_bytes();
+            if b.len() == 2 && b[0] == b'r' && (b'0'..=b'7').contains(&b[1]) {
+                return Ok(Operand::Register(b[1] - b'0'));
+            }
+        }
+        Ok(Operand::Expr(expr::parse(&plain(tokens))?))
+    }
+
+    /// Parses a statement; labels and `.equ` are recorded as a side effect.
+    fn parse(&mut self, line: &Line, addr: u32) -> Result<Option<Item>, AsmError> {
+        let number = line.number;
+        let err = |message: String| AsmError { line: number, message };
+        let tokens = lexer::tokenize(&line.text).map_err(err)?;
+
+        let labels = label_tokens(&tokens);
+        for pair in tokens[..labels].chunks(2) {
+            if let Token::Ident(name) = &pair[0].token {
+                self.define(name, Symbol::Label(addr), number)?;
+            }
+        }
+        let head = match tokens.get(labels).map(|t| &t.token) {
+            None => return Ok(None),
+            Some(Token::Ident(head)) => head.clone(),
+            Some(t) => return Err(err(format!("unexpected {:?}", t))),
+        };
+        let operands = split_operands(&tokens[labels + 1..]);
+
+        let item = match head.as_str() {
+            ".equ" => {
+                let (name, value) = match operands.as_slice() {
+                    [[Spanned { token: Token::Ident(name), .. }], value] => (name.clone(), *value),
+                    _ => return Err(err(".equ needs a name and a value".to_string())),
+                };
+                let expr = expr::parse(&plain(value)).map_err(err)?;
+                self.define(&name, Symbol::Const { expr, line: number, here: addr }, number)?;
+                return Ok(None);
+            }
+            ".word" => {
+                if operands.is_empty() {
+                    return Err(err(".word needs at least one value".to_string()));
+                }
+                let words = operands.iter().map(|o| expr::parse(&plain(o)));
+                Item::Words(words.collect::<Result<_, _>>().map_err(err)?)
+            }
+            ".string" => match operands.as_slice() {
+                [[Spanned { token: Token::Str(bytes), .. }]] => Item::Bytes(bytes.clone()),
+                _ => return Err(err(".string needs one string literal".to_string())),
+            },
+            ".zero" => {
+                let [count] = operands.as_slice() else {
+                    return Err(err(".zero needs a count".to_string()));
+                };
+                let count = expr::parse(&plain(count)).map_err(err)?;
+                let count = self.eval(&count, addr).map_err(err)?;
+                Item::Zero(u32::try_from(count).map_err(|_| err(format!("bad .zero count {}", count)))?)
+            }
+            ".temps" | ".link" => {
+                let registers = operands
+                    .iter()
+                    .map(|o| match Self::parse_operand(o) {
+                        Ok(Operand::Register(r)) => Ok(r),
+                        _ => Err(err(format!("{} takes registers", head))),
+                    })
+                    .collect::<Result<Vec<u8>, _>>()?;
+                match (head.as_str(), registers.as_slice()) {
+                    (".temps", &[a, b]) if a != b => self.conventions.temps = [a, b],
+                    (".link", &[l]) => self.conventions.link = l,
+                    _ if head == ".temps" => return Err(err(".temps needs two distinct registers".to_string())),
+                    _ => return Err(err(".link needs one register".to_string())),
+                }
+                return Ok(None);
+            }
+            _ if PSEUDO.contains(&head.as_str()) => {
+                let operands = operands
+                    .iter()
+                    .map(|o| Self::parse_operand(o))
+                    .collect::<Result<_, _>>()
+                    .map_err(err)?;
+                let mut pseudo = Pseudo {
+                    name: head,
+                    operands,
+                    conventions: self.conventions,
+                    long: false,
+                    len: 0,
+                };
+                let (code, long) = self.expand_pseudo(&pseudo, addr, true).map_err(err)?;
+                pseudo.long = long;
+                pseudo.len = code.len() as u32;
+                Item::Pseudo(pseudo)
+            }
+            _ => match MNEMONICS.iter().position(|m| *m == head) {
+                Some(op) => {
+                    let operands = operands
+                        .iter()
+                        .map(|o| Self::parse_operand(o))
+                        .collect::<Result<_, _>>()
+                        .map_err(err)?;
+                    Item::Op(op as u8, operands)
+                }
+                None if head.starts_with('.') => return Err(err(format!("unknown directive {}", head))),
+                None => return Err(err(format!("unknown instruction {}", head))),
+            },
+        };
+        Ok(Some(item))
+    }
+
+    /// Expands a pseudo-instruction at `here`. In the first pass values that
+    /// cannot be evaluated yet select the fixed-length expansions, which the
+    /// second pass then has to use too. Returns the code and whether it is
+    /// the fixed-length form.
+    fn expand_pseudo(&self, p: &Pseudo, here: u32, first_pass: bool) -> Result<(Vec<Instruction>, bool), String> {
+        let conv = &p.conventions;
+        let mut long = p.long;
+        let mut value = |o: &Operand| match o {
+            Operand::Expr(e) => match self.word(e, here) {
+                Err(_) if first_pass => {
+                    long = true;
+                    Ok(0)
+                }
+                v => v,
+            },
+            Operand::Register(r) => Err(format!("{} takes a value, not r{}", p.name, r)),
+        };
+
+        use Operand::{Expr as E, Register as R};
+        let code = match (p.name.as_str(), p.operands.as_slice()) {
+            ("li", [R(d), v]) => {
+                let v = value(v)?;
+                if long { conv.li_long(*d, v)? } else { conv.li(*d, v)? }
+            }
+            ("not", [R(d), R(a)]) => conv.not(*d, *a),
+            ("and", [R(d), R(a), R(b)]) => conv.and(*d, *a, *b),
+            ("or", [R(d), R(a), R(b)]) => conv.or(*d, *a, *b)?,
+            ("sub", [R(d), R(a), R(b)]) => conv.sub(*d, *a, *b)?,
+            ("jmp", [R(t)]) => conv.jmp_reg(*t)?,
+            ("jmp", [t @ E(_)]) => {
+                let t = value(t)?;
+                if long { conv.jmp_long(t)? } else { conv.jmp(t)? }
+            }
+            ("call", [R(t)]) => conv.call_reg(*t, here)?,
+            ("call", [t @ E(_)]) => {
+                let t = value(t)?;
+                if long { conv.call_long(t, here)? } else { conv.call(t, here)? }
+            }
+            ("ret", []) => conv.ret()?,
+            (name, _) => {
+                let form = match name {
+                    "li" => "a register and a value",
+                    "not" => "two registers",
+                    "and" | "or" | "sub" => "three registers",
+                    "jmp" | "call" => "a register or an address",
+                    _ => "no operands",
+                };
+                return Err(format!("{} takes {}", name, form));
+            }
+        };
+        Ok((code, long))
+    }
+
+    fn encode_op(&self, op: u8, operands: &[Operand], here: u32) -> Result<u32, String> {
+        let name = MNEMONICS[op as usize];
+        let registers = |n: usize| -> Result<Vec<u32>, String> {
+            let regs: Vec<u32> = operands
+                .iter()
+                .filter_map(|o| match o {
+                    Operand::Register(r) => Some(*r as u32),
+                    Operand::Expr(_) => None,
+                })
+                .collect();
+            if regs.len() != operands.len() || regs.len() != n {
+                return Err(format!("{} takes {} register operand(s)", name, n));
+            }
+            Ok(regs)
+        };
+        let op32 = (op as u32) << 28;
+        Ok(match op {
+            0..=6 => {
+                let r = registers(3)?;
+                op32 | r[0] << 6 | r[1] << 3 | r[2]
+            }
+            7 => {
+                registers(0)?;
+                op32
+            }
+            8 | 12 => {
+                let r = registers(2)?;
+                op32 | r[0] << 3 | r[1]
+            }
+            9..=11 => op32 | registers(1)?[0],
+            _ => match operands {
+                [Operand::Register(a), Operand::Expr(e)] => {
+                    let v = self.eval(e, here)?;
+                    if !(0..1 << 25).contains(&v) {
+                        return Err(format!("{} does not fit in 25 bits", v));
+                    }
+                    op32 | (*a as u32) << 25 | v as u32
+                }
+                _ => return Err("ortho takes a register and a value".to_string()),
+            },
+        })
+    }
+
+    fn assemble(&mut self, source: &str) -> Result<Vec<u32>, AsmError> {
+        let lines = self.expand(source)?;
+
+        let mut statements = vec![];
+        let mut addr: u32 = 0;
+        for line in &lines {
+            if let Some(item) = self.parse(line, addr)? {
+                let len = item.len();
+                statements.push(Statement { line: line.number, addr, item });
+                addr = addr.checked_add(len).ok_or(AsmError {
+                    line: line.number,
+                    message: "program too large".to_string(),
+                })?;
+            }
+        }
+
+        let mut image = Vec::with_capacity(addr as usize);
+        for s in &statements {
+            let err = |message: String| AsmError { line: s.line, message };
+            match &s.item {
+                Item::Op(op, operands) => image.push(self.encode_op(*op, operands, s.addr).map_err(err)?),
+                Item::Pseudo(p) => {
+                    let (code, _) = self.expand_pseudo(p, s.addr, false).map_err(err)?;
+                    debug_assert_eq!(p.len as usize, code.len());
+                    image.extend(code.iter().map(Instruction::encode));
+                }
+                Item::Words(words) => {
+                    for w in words {
+                        image.push(self.word(w, s.addr).map_err(err)?);
+                    }
+                }
+                Item::Bytes(bytes) => image.extend(bytes.iter().map(|&b| b as u32)),
+                Item::Zero(n) => image.resize(image.len() + *n as usize, 0),
+            }
+        }
+
+        // Report constants that are never used but cannot be evaluated.
+        for (name, symbol) in &self.symbols {
+            if let Symbol::Const { line, .. } = symbol {
+                self.lookup(name, 0).map_err(|message| AsmError { line: *line, message })?;
+            }
+        }
+        Ok(image)
+    }
+}
+
+/// Assembles `source` into platters.
+pub fn assemble(source: &str) -> Result<Vec<u32>, AsmError> {
+    Assembler::new().assemble(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::IoConsole;
+    use crate::UM;
+
+    fn run(source: &str) -> Vec<u8> {
+        let image = assemble(source).unwrap();
+        let mut um = UM::with_console(image, IoConsole::new(&b""[..], vec![]));
+        um.spin_cycle().unwrap();
+        um.into_console().into_inner().1
+    }
+
+    #[test]
+    fn test_hello() {
+        let source = "
+            .equ NEWLINE, 10
+            .macro print reg, char
+                    ortho \\reg, \\char
+                    out \\reg
+            .endm
+
+            start:  print r1, 'H'
+                    print r1, 'i' ; comment
+                    print r1, NEWLINE
+                    halt
+        ";
+        assert_eq!(b"Hi\n", run(source).as_slice());
+    }
+
+    #[test]
+    fn test_labels_and_data() {
+        let source = "
+                    ortho r1, text
+                    ortho r2, 1
+                    ortho r3, end
+            loop:   index r4, r0, r1
+                    out r4
+                    add r1, r1, r2
+                    ortho r5, loop
+                    ortho r6, done
+                    cmov r6, r5, r7   ; r7 is zero, so this never jumps
+                    load r0, r6
+            done:   halt
+            text:   .string \"!\"
+            end:    .word end - text, -1
+                    .zero 2
+        ";
+        let image = assemble(source).unwrap();
+        assert_eq!(16, image.len());
+        assert_eq!(&[b'!' as u32, 1, 0xffff_ffff, 0, 0], &image[11..]);
+        assert_eq!(b"!", run(source).as_slice());
+    }
+
+    #[test]
+    fn test_local_labels() {
+        let source = "
+            .macro skip reg
+                    ortho \\reg, next\\@
+                    load r0, \\reg
+            next\\@:
+            .endm
+                    skip r1
+                    skip r1
+                    halt
+        ";
+        assert_eq!(5, assemble(source).unwrap().len());
+    }
+
+    #[test]
+    fn test_pseudo_instructions() {
+        let source = "
+                    li r3, 0xdeadbeef
+                    li r4, far        ; forward, so sized for any value
+                    sub r1, r3, r4
+                    call print
+                    li r1, 'k'
+                    call print
+                    halt
+            print:  out r1
+                    ret
+            .equ far, 0xdeadbeef - 'a'
+        ";
+        assert_eq!(b"ak", run(source).as_slice());
+        assert_eq!(5 + 5 + 4 + 8 + 1 + 8 + 1 + 1 + 2, assemble(source).unwrap().len());
+        assert_eq!(2, assemble(".link r1\ncall r1").unwrap_err().line);
+        assert_eq!("li takes a register and a value", assemble("li 1, r1").unwrap_err().message);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source: &str| assemble(source).unwrap_err();
+        assert_eq!(2, error("halt\nadd r1, r2").line);
+        assert_eq!("unknown instruction jump", error("jump r1").message);
+        assert_eq!("undefined symbol nowhere", error("ortho r1, nowhere").message);
+        assert_eq!("33554432 does not fit in 25 bits", error("ortho r1, 1 << 25").message);
+        assert_eq!("x is already defined", error("x: halt\nx: halt").message);
+        assert_eq!(4, error(".macro m a\nout \\a\n.endm\nm r1, r2").line);
+        assert_eq!(1, error(".macro m\nhalt").line);
+    }
+}
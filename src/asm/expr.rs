@@ -0,0 +1,135 @@
+//! Constant expressions over numbers, labels and `.equ` symbols.
+
+use super::lexer::Token;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Expr {
+    Number(i64),
+    Symbol(String),
+    /// `$`, the address of the current statement.
+    Here,
+    Unary(&'static str, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+/// Binary operators from loosest to tightest binding.
+const LEVELS: [&[&str]; 6] = [&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"], &["*", "/", "%"]];
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(&Token::Punct(op)) = self.peek() {
+            if !LEVELS[level].contains(&op) {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Punct(op @ ("-" | "~"))) => Ok(Expr::Unary(op, Box::new(self.unary()?))),
+            Some(Token::Punct("(")) => {
+                let e = self.binary(0)?;
+                match self.peek() {
+                    Some(Token::Punct(")")) => {
+                        self.pos += 1;
+                        Ok(e)
+                    }
+                    _ => Err("expected )".to_string()),
+                }
+            }
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Ident(name)) if name == "$" => Ok(Expr::Here),
+            Some(Token::Ident(name)) => Ok(Expr::Symbol(name)),
+            Some(t) => Err(format!("unexpected {:?} in expression", t)),
+            None => Err("expected an expression".to_string()),
+        }
+    }
+}
+
+pub(crate) fn parse(tokens: &[Token]) -> Result<Expr, String> {
+    let mut parser = Parser { tokens, pos: 0 };
+    let e = parser.binary(0)?;
+    match parser.peek() {
+        None => Ok(e),
+        Some(t) => Err(format!("unexpected {:?} after expression", t)),
+    }
+}
+
+impl Expr {
+    /// Evaluates with 64-bit intermediate results; `lookup` resolves symbols.
+    pub fn eval(&self, here: u32, lookup: &mut dyn FnMut(&str) -> Result<i64, String>) -> Result<i64, String> {
+        Ok(match self {
+            Expr::Number(n) => *n,
+            Expr::Symbol(name) => lookup(name)?,
+            Expr::Here => here as i64,
+            Expr::Unary(op, e) => {
+                let v = e.eval(here, lookup)?;
+                if *op == "-" { v.wrapping_neg() } else { !v }
+            }
+            Expr::Binary(op, l, r) => {
+                let l = l.eval(here, lookup)?;
+                let r = r.eval(here, lookup)?;
+                match *op {
+                    "|" => l | r,
+                    "^" => l ^ r,
+                    "&" => l & r,
+                    "<<" => l.wrapping_shl(r as u32),
+                    ">>" => l.wrapping_shr(r as u32),
+                    "+" => l.wrapping_add(r),
+                    "-" => l.wrapping_sub(r),
+                    "*" => l.wrapping_mul(r),
+                    _ if r == 0 => return Err("division by zero in expression".to_string()),
+                    "/" => l.wrapping_div(r),
+                    _ => l.wrapping_rem(r),
+                }
+            }
+        })
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::lexer::tokenize;
+
+    fn eval(text: &str) -> Result<i64, String> {
+        let tokens: Vec<Token> = tokenize(text)?.into_iter().map(|t| t.token).collect();
+        parse(&tokens)?.eval(100, &mut |name| match name {
+            "x" => Ok(7),
+            _ => Err(format!("undefined symbol {}", name)),
+        })
+    }
+
+    #[test]
+    fn test_eval() {
+        assert_eq!(Ok(14), eval("2 + 3 * 4"));
+        assert_eq!(Ok(20), eval("(2 + 3) * 4"));
+        assert_eq!(Ok(0x10), eval("1 << 2 + 2"));
+        assert_eq!(Ok(-8), eval("~x"));
+        assert_eq!(Ok(107), eval("$ + x"));
+        assert_eq!(Ok(65), eval("'A'"));
+        assert!(eval("y").is_err());
+        assert!(eval("1 / 0").is_err());
+        assert!(eval("(1").is_err());
+    }
+}
@@ -0,0 +1,176 @@
+//! Tokens of a single source line.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Token {
+    Ident(String),
+    Number(i64),
+    Str(Vec<u8>),
+    Punct(&'static str),
+}
+
+/// A token and the byte range it came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Spanned {
+    pub token: Token,
+    pub start: usize,
+    pub end: usize,
+}
+
+const PUNCTS: [&str; 15] = [
+    "<<", ">>", "+", "-", "*", "/", "%", "&", "|", "^", "~", "(", ")", ",", ":",
+];
+
+fn is_ident_start(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_' || c == b'.' || c == b'$' || c == b'\\'
+}
+
+fn is_ident(c: u8) -> bool {
+    is_ident_start(c) || c.is_ascii_digit() || c == b'@'
+}
+
+/// Reads one escape sequence after a backslash, returning the byte and the
+/// position after it.
+fn escape(s: &[u8], i: usize) -> Result<(u8, usize), String> {
+    match s.get(i) {
+        Some(b'n') => Ok((b'\n', i + 1)),
+        Some(b't') => Ok((b'\t', i + 1)),
+        Some(b'r') => Ok((b'\r', i + 1)),
+        Some(b'0') => Ok((0, i + 1)),
+        Some(b'\\') => Ok((b'\\', i + 1)),
+        Some(b'\'') => Ok((b'\'', i + 1)),
+        Some(b'"') => Ok((b'"', i + 1)),
+        Some(b'x') => {
+            let hex = s.get(i + 1..i + 3).and_then(|h| std::str::from_utf8(h).ok());
+            match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                Some(b) => Ok((b, i + 3)),
+                None => Err("bad \\x escape".to_string()),
+            }
+        }
+        Some(&c) => Err(format!("unknown escape \\{}", c as char)),
+        None => Err("unterminated escape".to_string()),
+    }
+}
+
+/// Returns `line` without its `;` comment, ignoring semicolons in literals.
+pub(crate) fn strip_comment(line: &str) -> &str {
+    let s = line.as_bytes();
+    let mut quote = None;
+    let mut i = 0;
+    while i < s.len() {
+        match (quote, s[i]) {
+            (None, b';') => return &line[..i],
+            (None, q @ (b'"' | b'\'')) => quote = Some(q),
+            (Some(_), b'\\') => i += 1,
+            (Some(q), c) if c == q => quote = None,
+            _ => {}
+        }
+        i += 1;
+    }
+    line
+}
+
+pub(crate) fn tokenize(line: &str) -> Result<Vec<Spanned>, String> {
+    let s = line.as_bytes();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < s.len() {
+        let c = s[i];
+        let start = i;
+        let token = if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        } else if c.is_ascii_digit() {
+            while i < s.len() && (s[i].is_ascii_alphanumeric() || s[i] == b'_') {
+                i += 1;
+            }
+            let text = line[start..i].replace('_', "");
+            let parsed = if let Some(hex) = text.strip_prefix("0x") {
+                i64::from_str_radix(hex, 16)
+            } else if let Some(bin) = text.strip_prefix("0b") {
+                i64::from_str_radix(bin, 2)
+            } else {
+                text.parse()
+            };
+            Token::Number(parsed.map_err(|_| format!("bad number {}", &line[start..i]))?)
+        } else if is_ident_start(c) {
+            while i < s.len() && is_ident(s[i]) {
+                i += 1;
+            }
+            Token::Ident(line[start..i].to_string())
+        } else if c == b'"' {
+            let mut bytes = vec![];
+            i += 1;
+            loop {
+                match s.get(i) {
+                    Some(b'"') => break,
+                    Some(b'\\') => {
+                        let (b, next) = escape(s, i + 1)?;
+                        bytes.push(b);
+                        i = next;
+                    }
+                    Some(&b) => {
+                        bytes.push(b);
+                        i += 1;
+                    }
+                    None => return Err("unterminated string".to_string()),
+                }
+            }
+            i += 1;
+            Token::Str(bytes)
+        } else if c == b'\'' {
+            let (b, next) = match s.get(i + 1) {
+                Some(b'\\') => escape(s, i + 2)?,
+                Some(&b) => (b, i + 2),
+                None => return Err("unterminated character".to_string()),
+            };
+            if s.get(next) != Some(&b'\'') {
+                return Err("unterminated character".to_string());
+            }
+            i = next + 1;
+            Token::Number(b as i64)
+        } else {
+            let punct = PUNCTS
+                .iter()
+                .find(|p| s[i..].starts_with(p.as_bytes()))
+                .ok_or_else(|| format!("unexpected character {:?}", c as char))?;
+            i += punct.len();
+            Token::Punct(punct)
+        };
+        tokens.push(Spanned { token, start, end: i });
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(line: &str) -> Vec<Token> {
+        tokenize(line).unwrap().into_iter().map(|t| t.token).collect()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            vec![
+                Token::Ident("loop".to_string()),
+                Token::Punct(":"),
+                Token::Ident("ortho".to_string()),
+                Token::Ident("r1".to_string()),
+                Token::Punct(","),
+                Token::Number(0x1f),
+                Token::Punct("<<"),
+                Token::Number(65),
+            ],
+            tokens("loop: ortho r1, 0x1f << 'A' - 55")[..8].to_vec()
+        );
+        assert_eq!(vec![Token::Str(b"a;\n".to_vec())], tokens(r#""a;\n""#));
+    }
+
+    #[test]
+    fn test_strip_comment() {
+        assert_eq!(".string \"a;b\" ", strip_comment(".string \"a;b\" ; text"));
+        assert_eq!("ortho r1, ';'", strip_comment("ortho r1, ';'"));
+    }
+}
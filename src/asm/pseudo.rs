@@ -0,0 +1,218 @@
+//! Pseudo-instructions that expand to sequences of real operators.
+//!
+//! Expansions may clobber the two temporary registers of their
+//! `Conventions`, and `call`/`ret` pass the return address in its link
+//! register. Subroutines are entered with Load Program from array 0, so a
+//! call is only a jump that first records where to come back to.
+
+use crate::instruction::Instruction;
+use crate::instruction::Instruction::*;
+
+/// Registers reserved for expansions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Conventions {
+    /// Clobbered by `li` with a large value, `or`, `sub`, `jmp`, `call` and `ret`.
+    pub temps: [u8; 2],
+    /// Holds the return address set by `call` and used by `ret`.
+    pub link: u8,
+}
+
+impl Default for Conventions {
+    fn default() -> Self {
+        Conventions { temps: [6, 7], link: 5 }
+    }
+}
+
+const ORTHOGRAPHY_LIMIT: u32 = 1 << 25;
+
+fn ortho(a: u8, value: u32) -> Instruction {
+    Orthography { a, value }
+}
+
+fn nand(a: u8, b: u8, c: u8) -> Instruction {
+    NotAnd { a, b, c }
+}
+
+impl Conventions {
+    /// A temporary register that is none of `avoid`.
+    fn scratch(&self, avoid: &[u8]) -> Result<u8, String> {
+        self.temps
+            .into_iter()
+            .find(|t| !avoid.contains(t))
+            .ok_or_else(|| format!("both temporaries r{} and r{} are operands", self.temps[0], self.temps[1]))
+    }
+
+    /// Loads any 32-bit value, in one instruction when it fits Orthography.
+    pub fn li(&self, dst: u8, value: u32) -> Result<Vec<Instruction>, String> {
+        if value < ORTHOGRAPHY_LIMIT {
+            Ok(vec![ortho(dst, value)])
+        } else if !value < ORTHOGRAPHY_LIMIT {
+            Ok(vec![ortho(dst, !value), nand(dst, dst, dst)])
+        } else {
+            self.li_long(dst, value)
+        }
+    }
+
+    /// Loads any 32-bit value in exactly five instructions.
+    pub(crate) fn li_long(&self, dst: u8, value: u32) -> Result<Vec<Instruction>, String> {
+        let t = self.scratch(&[dst])?;
+        Ok(vec![
+            ortho(dst, value >> 16),
+            ortho(t, 1 << 16),
+            Multiplication { a: dst, b: dst, c: t },
+            ortho(t, value & 0xffff),
+            Addition { a: dst, b: dst, c: t },
+        ])
+    }
+
+    pub fn not(&self, dst: u8, src: u8) -> Vec<Instruction> {
+        vec![nand(dst, src, src)]
+    }
+
+    pub fn and(&self, dst: u8, a: u8, b: u8) -> Vec<Instruction> {
+        vec![nand(dst, a, b), nand(dst, dst, dst)]
+    }
+
+    pub fn or(&self, dst: u8, a: u8, b: u8) -> Result<Vec<Instruction>, String> {
+        let t = self.scratch(&[dst, a, b])?;
+        Ok(vec![nand(t, a, a), nand(dst, b, b), nand(dst, t, dst)])
+    }
+
+    /// `dst = a - b`, computed as `a + !b + 1`.
+    pub fn sub(&self, dst: u8, a: u8, b: u8) -> Result<Vec<Instruction>, String> {
+        let t = self.scratch(&[dst, a, b])?;
+        Ok(vec![
+            nand(t, b, b),
+            Addition { a: dst, b: a, c: t },
+            ortho(t, 1),
+            Addition { a: dst, b: dst, c: t },
+        ])
+    }
+
+    /// Jumps to the address held in `target`.
+    pub fn jmp_reg(&self, target: u8) -> Result<Vec<Instruction>, String> {
+        let zero = self.scratch(&[target])?;
+        Ok(vec![ortho(zero, 0), LoadProgram { a: 0, b: zero, c: target }])
+    }
+
+    /// Jumps to an address in array 0.
+    pub fn jmp(&self, target: u32) -> Result<Vec<Instruction>, String> {
+        self.jmp_with(target, false)
+    }
+
+    fn jmp_with(&self, target: u32, long: bool) -> Result<Vec<Instruction>, String> {
+        let [t, _] = self.temps;
+        let mut code = if long { self.li_long(t, target)? } else { self.li(t, target)? };
+        code.extend(self.jmp_reg(t)?);
+        Ok(code)
+    }
+
+    /// Jumps to `target` with the address following the sequence, which
+    /// starts at `here`, in the link register.
+    pub fn call(&self, target: u32, here: u32) -> Result<Vec<Instruction>, String> {
+        self.call_with(target, here, false)
+    }
+
+    fn call_with(&self, target: u32, here: u32, long: bool) -> Result<Vec<Instruction>, String> {
+        if self.temps.contains(&self.link) {
+            return Err(format!("link register r{} is also a temporary", self.link));
+        }
+        let jump = self.jmp_with(target, long)?;
+        let back = here + 1 + jump.len() as u32;
+        if back >= ORTHOGRAPHY_LIMIT {
+            return Err(format!("return address {} does not fit in 25 bits", back));
+        }
+        let mut code = vec![ortho(self.link, back)];
+        code.extend(jump);
+        Ok(code)
+    }
+
+    /// Calls the address held in `target`.
+    pub fn call_reg(&self, target: u8, here: u32) -> Result<Vec<Instruction>, String> {
+        if target == self.link {
+            return Err(format!("cannot call through the link register r{}", self.link));
+        }
+        let jump = self.jmp_reg(target)?;
+        let back = here + 1 + jump.len() as u32;
+        if back >= ORTHOGRAPHY_LIMIT {
+            return Err(format!("return address {} does not fit in 25 bits", back));
+        }
+        let mut code = vec![ortho(self.link, back)];
+        code.extend(jump);
+        Ok(code)
+    }
+
+    pub fn ret(&self) -> Result<Vec<Instruction>, String> {
+        self.jmp_reg(self.link)
+    }
+
+    /// The fixed-length forms used while a target address is still unknown.
+    pub(crate) fn jmp_long(&self, target: u32) -> Result<Vec<Instruction>, String> {
+        self.jmp_with(target, true)
+    }
+
+    pub(crate) fn call_long(&self, target: u32, here: u32) -> Result<Vec<Instruction>, String> {
+        self.call_with(target, here, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Outcome, UM};
+
+    const LONG_LI: usize = 5;
+
+    fn run(code: Vec<Instruction>) -> UM {
+        let mut image: Vec<u32> = code.iter().map(Instruction::encode).collect();
+        image.push(Halt { a: 0, b: 0, c: 0 }.encode());
+        let mut um = UM::new(image);
+        assert_eq!(Outcome::Halted, um.run_until(100));
+        um
+    }
+
+    #[test]
+    fn test_li() {
+        let conv = Conventions::default();
+        for value in [0, 0x41, 0x1ff_ffff, 0x200_0000, 0xdead_beef, 0xffff_ffff, 0xfe00_0000] {
+            let code = conv.li(1, value).unwrap();
+            assert_eq!(value, run(code).registers()[1], "{:#x}", value);
+            assert_eq!(value, run(conv.li_long(6, value).unwrap()).registers()[6]);
+        }
+        assert_eq!(1, conv.li(1, 5).unwrap().len());
+        assert_eq!(2, conv.li(1, !5).unwrap().len());
+        assert_eq!(LONG_LI, conv.li(1, 0x8000_0000).unwrap().len());
+    }
+
+    #[test]
+    fn test_logic() {
+        let conv = Conventions::default();
+        let setup = |mut code: Vec<Instruction>| {
+            let mut all = conv.li(1, 0b1100).unwrap();
+            all.extend(conv.li(2, 0b1010).unwrap());
+            all.append(&mut code);
+            run(all).registers()[3]
+        };
+        assert_eq!(!0b1100, setup(conv.not(3, 1)));
+        assert_eq!(0b1000, setup(conv.and(3, 1, 2)));
+        assert_eq!(0b1110, setup(conv.or(3, 1, 2).unwrap()));
+        assert_eq!(2, setup(conv.sub(3, 1, 2).unwrap()));
+        assert_eq!(2u32.wrapping_neg(), setup(conv.sub(3, 2, 1).unwrap()));
+        assert!(conv.or(6, 7, 1).is_err());
+    }
+
+    #[test]
+    fn test_call_and_ret() {
+        let conv = Conventions::default();
+        // 0: call 10; then r1 <- 1; halt.  10: r2 <- 2; ret
+        let mut code = conv.call(10, 0).unwrap();
+        code.push(ortho(1, 1));
+        code.push(Halt { a: 0, b: 0, c: 0 });
+        code.resize(10, Halt { a: 0, b: 0, c: 0 });
+        code.push(ortho(2, 2));
+        code.extend(conv.ret().unwrap());
+        let um = run(code);
+        assert_eq!([1, 2], um.registers()[1..3]);
+        assert_eq!(1 + LONG_LI + 2, conv.call_long(10, 0).unwrap().len());
+    }
+}
@@ -0,0 +1,59 @@
+//! Pre-decoded form of array 0, so the hot loop does not re-extract fields.
+
+use crate::platter::*;
+
+/// A decoded platter. For Orthography `a` is the target register and `value`
+/// the immediate; for the other operators `value` is unused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Op {
+    pub code: u8,
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub value: u32,
+}
+
+impl Op {
+    #[inline]
+    pub fn decode(p: u32) -> Op {
+        let code = op_code(p);
+        if code == 13 {
+            Op { code, a: rego_offset(p) as u8, b: 0, c: 0, value: rego_value(p) }
+        } else {
+            Op {
+                code,
+                a: rega_offset(p) as u8,
+                b: regb_offset(p) as u8,
+                c: regc_offset(p) as u8,
+                value: 0,
+            }
+        }
+    }
+
+    /// Whether the operator only reads and writes registers.
+    #[inline]
+    pub fn is_register_op(&self) -> bool {
+        matches!(self.code, 0 | 3 | 4 | 5 | 6 | 13)
+    }
+}
+
+pub(crate) fn decode_all(platters: &[u32]) -> Vec<Op> {
+    platters.iter().map(|&p| Op::decode(p)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            Op { code: 3, a: 1, b: 2, c: 3, value: 0 },
+            Op::decode(make_platter(3, 1, 2, 3))
+        );
+        assert_eq!(
+            Op { code: 13, a: 7, b: 0, c: 0, value: 0x1ffffff },
+            Op::decode(make_orthography(7, 0x1ffffff))
+        );
+    }
+}
@@ -0,0 +1,159 @@
+//! Extraction of the UMIX image from the codex.
+//!
+//! The codex asks for a decryption key, then offers a menu whose dump option
+//! prints `MARKER` followed by the image of the program it contains.
+//! `extract` answers both and returns the bytes after the marker.
+
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+
+use crate::console::Console;
+use crate::fault::Error;
+use crate::um::{Outcome, UM};
+
+/// The decryption key published with the codex.
+pub const KEY: &str = r"(\b.bb)(\v.vv)06FHPVboundvarHRAk";
+
+/// Menu choice that dumps the program.
+pub const DUMP_OPTION: &str = "p";
+
+/// Text the codex prints just before the image.
+pub const MARKER: &[u8] = b"UM program follows colon:";
+
+#[derive(Debug)]
+pub enum ExtractError {
+    Machine(Error),
+    /// The codex stopped or wanted more input without printing `MARKER`.
+    NoMarker,
+    /// The dump is not a whole, nonzero number of platters.
+    BadLength(usize),
+}
+
+impl fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExtractError::Machine(e) => e.fmt(f),
+            ExtractError::NoMarker => write!(f, "the codex did not dump a program; is the key right?"),
+            ExtractError::BadLength(n) => write!(f, "the dumped program is {} bytes, not a whole number of platters", n),
+        }
+    }
+}
+
+impl StdError for ExtractError {}
+
+impl From<Error> for ExtractError {
+    fn from(e: Error) -> Self {
+        ExtractError::Machine(e)
+    }
+}
+
+impl From<io::Error> for ExtractError {
+    fn from(e: io::Error) -> Self {
+        ExtractError::Machine(e.into())
+    }
+}
+
+/// Runs the codex in `um` with `key`, selects the dump option and returns
+/// the image it prints. What the codex prints before the image goes to the
+/// console; the console's input is not used.
+///
+/// The machine stops at the first Input after the dump has begun.
+pub fn extract<C: Console>(um: &mut UM<C>, key: &str) -> Result<Vec<u8>, ExtractError> {
+    let result = dump(um, key);
+    um.console_mut().flush()?;
+    let image = result?.ok_or(ExtractError::NoMarker)?;
+    if image.is_empty() || image.len() % 4 != 0 {
+        return Err(ExtractError::BadLength(image.len()));
+    }
+    Ok(image)
+}
+
+fn dump<C: Console>(um: &mut UM<C>, key: &str) -> Result<Option<Vec<u8>>, Error> {
+    let script = format!("{}\n{}\n", key, DUMP_OPTION);
+    let mut input = script.bytes();
+    let mut text = vec![];
+    let mut image: Option<Vec<u8>> = None;
+    loop {
+        let outcome = um.run_until(um.until_flush());
+        match outcome {
+            Outcome::Output(byte) => match &mut image {
+                Some(image) => {
+                    image.push(byte);
+                    continue;
+                }
+                None => {
+                    text.push(byte);
+                    if text.ends_with(MARKER) {
+                        image = Some(vec![]);
+                    }
+                }
+            },
+            Outcome::NeedsInput => match input.next() {
+                Some(byte) if image.is_none() => {
+                    um.provide_input(byte);
+                    um.flush_if_due()?;
+                    continue;
+                }
+                _ => return Ok(image),
+            },
+            _ => {}
+        }
+        match um.service(outcome)? {
+            Some(Outcome::Fault(fault)) => return Err(fault.into()),
+            Some(_) => return Ok(image),
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::IoConsole;
+    use crate::platter::{make_orthography, make_platter};
+
+    /// Echoes two bytes of input, prints the marker and `dump`, then waits
+    /// for input again.
+    fn fake_codex(dump: &[u8]) -> Vec<u32> {
+        let mut program = vec![
+            make_platter(11, 0, 0, 1),
+            make_platter(10, 0, 0, 1),
+            make_platter(11, 0, 0, 1),
+            make_platter(10, 0, 0, 1),
+        ];
+        for &byte in MARKER.iter().chain(dump) {
+            program.push(make_orthography(1, byte as u32));
+            program.push(make_platter(10, 0, 0, 1));
+        }
+        program.push(make_platter(11, 0, 0, 1));
+        program.push(make_platter(7, 0, 0, 0));
+        program
+    }
+
+    fn extracted(program: Vec<u32>) -> (Result<Vec<u8>, ExtractError>, Vec<u8>) {
+        let mut um = UM::with_console(program, IoConsole::new(&b""[..], vec![]));
+        let result = extract(&mut um, "k");
+        (result, um.into_console().into_inner().1)
+    }
+
+    #[test]
+    fn test_extract() {
+        let (image, text) = extracted(fake_codex(&[0x70, 0, 0, 0, 0xff, 0, 0, 1]));
+        assert_eq!(vec![0x70, 0, 0, 0, 0xff, 0, 0, 1], image.unwrap());
+        assert_eq!([b"k\n", MARKER].concat(), text);
+    }
+
+    #[test]
+    fn test_extract_errors() {
+        let (result, _) = extracted(fake_codex(&[0x70, 0, 0]));
+        assert!(matches!(result, Err(ExtractError::BadLength(3))));
+
+        // Asks for more input than the key and menu choice.
+        let (result, _) = extracted(vec![make_platter(11, 0, 0, 1), make_platter(12, 0, 0, 0)]);
+        assert!(matches!(result, Err(ExtractError::NoMarker)));
+
+        let (result, _) = extracted(vec![make_platter(5, 1, 1, 2)]);
+        assert!(matches!(result, Err(ExtractError::Machine(Error::Fault(_)))));
+    }
+}
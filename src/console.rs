@@ -0,0 +1,227 @@
+//! Byte-oriented I/O backends for the Output and Input operators.
+
+use std::io;
+use std::io::{BufWriter, Chain, Read, Stdin, Stdout, Write};
+use std::str::FromStr;
+
+/// The device attached to the Output (10) and Input (11) operators.
+pub trait Console {
+    /// Emits a single byte.
+    fn output(&mut self, byte: u8) -> io::Result<()>;
+
+    /// Reads a single byte, or `None` once the input is exhausted.
+    ///
+    /// Implementations that buffer output flush it before blocking here.
+    fn input(&mut self) -> io::Result<Option<u8>>;
+
+    /// Pushes any buffered output to its destination.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<C: Console + ?Sized> Console for &mut C {
+    fn output(&mut self, byte: u8) -> io::Result<()> {
+        (**self).output(byte)
+    }
+
+    fn input(&mut self) -> io::Result<Option<u8>> {
+        (**self).input()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+}
+
+impl<C: Console + ?Sized> Console for Box<C> {
+    fn output(&mut self, byte: u8) -> io::Result<()> {
+        (**self).output(byte)
+    }
+
+    fn input(&mut self) -> io::Result<Option<u8>> {
+        (**self).input()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+}
+
+/// How output bytes are rendered on the writer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputMode {
+    /// The exact byte, suitable for binary output.
+    #[default]
+    Raw,
+    /// Each byte as a Latin-1 character encoded in UTF-8.
+    Latin1,
+    /// Printable ASCII and whitespace as-is, everything else as `\xNN`.
+    Escaped,
+}
+
+impl FromStr for OutputMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(OutputMode::Raw),
+            "latin1" => Ok(OutputMode::Latin1),
+            "escaped" => Ok(OutputMode::Escaped),
+            _ => Err(format!("unknown output mode: {}", s)),
+        }
+    }
+}
+
+/// A console over any pair of `Read` and `Write` streams.
+#[derive(Debug)]
+pub struct IoConsole<R, W> {
+    reader: R,
+    writer: W,
+    mode: OutputMode,
+}
+
+/// Stdin and block-buffered stdout.
+pub type StdConsole = IoConsole<Stdin, BufWriter<Stdout>>;
+
+impl<R: Read, W: Write> IoConsole<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        IoConsole { reader, writer, mode: OutputMode::Raw }
+    }
+
+    pub fn with_output_mode(self, mode: OutputMode) -> Self {
+        IoConsole { mode, ..self }
+    }
+
+    pub fn reader(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn writer(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn into_inner(self) -> (R, W) {
+        (self.reader, self.writer)
+    }
+
+    /// Continues reading from `fallback` once the current input is exhausted,
+    /// instead of reporting end of input.
+    pub fn with_fallback<F: Read>(self, fallback: F) -> IoConsole<Chain<R, F>, W> {
+        IoConsole {
+            reader: self.reader.chain(fallback),
+            writer: self.writer,
+            mode: self.mode,
+        }
+    }
+}
+
+/// Prepares a file of scripted input: lines starting with `#` are dropped
+/// and, if `normalize_newlines` is set, `\r\n` and lone `\r` become `\n`.
+pub fn script_input(text: &[u8], normalize_newlines: bool) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(text.len());
+    let text = match normalize_newlines {
+        true => {
+            let mut bytes = text.iter().peekable();
+            while let Some(&byte) = bytes.next() {
+                match byte {
+                    b'\r' if bytes.peek() == Some(&&b'\n') => {}
+                    b'\r' => normalized.push(b'\n'),
+                    _ => normalized.push(byte),
+                }
+            }
+            &normalized
+        }
+        false => text,
+    };
+    let lines = text.split_inclusive(|&b| b == b'\n').filter(|line| !line.starts_with(b"#"));
+    lines.flatten().copied().collect()
+}
+
+impl Default for StdConsole {
+    fn default() -> Self {
+        IoConsole::new(io::stdin(), BufWriter::new(io::stdout()))
+    }
+}
+
+impl<R: Read, W: Write> Console for IoConsole<R, W> {
+    fn output(&mut self, byte: u8) -> io::Result<()> {
+        match self.mode {
+            OutputMode::Raw => self.writer.write_all(&[byte])?,
+            OutputMode::Latin1 => write!(self.writer, "{}", char::from(byte))?,
+            OutputMode::Escaped => match byte {
+                b'\\' => self.writer.write_all(b"\\\\")?,
+                b'\t' | b'\n' | b'\r' | 0x20..=0x7e => self.writer.write_all(&[byte])?,
+                _ => write!(self.writer, "\\x{:02x}", byte)?,
+            },
+        }
+        Ok(())
+    }
+
+    fn input(&mut self) -> io::Result<Option<u8>> {
+        self.writer.flush()?;
+        let mut buf = [0_u8];
+        match self.reader.read_exact(&mut buf) {
+            Ok(()) => Ok(Some(buf[0])),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_console() {
+        let mut console = IoConsole::new(&b"a"[..], vec![]);
+        assert_eq!(Some(b'a'), console.input().unwrap());
+        assert_eq!(None, console.input().unwrap());
+
+        console.output(b'z').unwrap();
+        assert_eq!(b"z", console.writer().as_slice());
+    }
+
+    #[test]
+    fn test_output_modes() {
+        let render = |mode| {
+            let mut console = IoConsole::new(&b""[..], vec![]).with_output_mode(mode);
+            for &byte in b"a\n\xe9\x01" {
+                console.output(byte).unwrap();
+            }
+            console.into_inner().1
+        };
+        assert_eq!(b"a\n\xe9\x01".to_vec(), render(OutputMode::Raw));
+        assert_eq!("a\n\u{e9}\u{1}".as_bytes().to_vec(), render(OutputMode::Latin1));
+        assert_eq!(b"a\n\\xe9\\x01".to_vec(), render(OutputMode::Escaped));
+    }
+
+    #[test]
+    fn test_flush_on_input() {
+        let mut console = IoConsole::new(&b"a"[..], BufWriter::new(vec![]));
+        console.output(b'>').unwrap();
+        assert!(console.writer().get_ref().is_empty());
+        console.input().unwrap();
+        assert_eq!(b">", console.writer().get_ref().as_slice());
+    }
+
+    #[test]
+    fn test_fallback_input() {
+        let mut console = IoConsole::new(&b"a"[..], vec![]).with_fallback(&b"b"[..]);
+        assert_eq!(Some(b'a'), console.input().unwrap());
+        assert_eq!(Some(b'b'), console.input().unwrap());
+        assert_eq!(None, console.input().unwrap());
+    }
+
+    #[test]
+    fn test_script_input() {
+        let script = b"# log in\r\nguest\r\n#\rls\r\n  # not a comment";
+        assert_eq!(b"guest\r\n  # not a comment".to_vec(), script_input(script, false));
+        assert_eq!(b"guest\nls\n  # not a comment".to_vec(), script_input(script, true));
+    }
+}
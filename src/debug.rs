@@ -0,0 +1,336 @@
+//! Interactive command-line debugger.
+//!
+//! The debugger drives the machine one `UM::step` at a time, so breakpoints
+//! cost nothing when it is not in use. Program output goes through the
+//! machine's console as usual; the console is flushed before the debugger
+//! prints anything, and a partial output line is ended first so prompts and
+//! listings always start on a fresh line.
+
+use std::collections::BTreeSet;
+use std::io;
+use std::io::Write;
+
+use crate::console::Console;
+use crate::disasm;
+use crate::fault::Fault;
+use crate::um::{Outcome, UM};
+
+const HELP: &str = "\
+break ADDR          b   stop when the finger reaches ADDR
+delete [ADDR]       d   remove one breakpoint, or all of them
+info                i   list breakpoints
+step [N]            s   execute N instructions (default 1)
+continue            c   run until a breakpoint, halt or fault
+regs                r   show registers, finger and cycle count
+list [N]            l   disassemble N instructions at the finger (default 5)
+x/N ARRAY OFFSET        dump N platters of an array (default 8)
+set rN VALUE            write a register
+set finger ADDR         move the finger
+set ARRAY OFFSET VALUE  write a platter
+help                h   show this text
+quit                q   leave the debugger";
+
+/// Why execution stopped and control came back to the prompt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Stop {
+    Stepped,
+    Breakpoint,
+    Halted,
+    Fault(Fault),
+}
+
+/// Parses a decimal or `0x` hexadecimal number.
+fn number(s: &str) -> Result<u32, String> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("bad number {}", s))
+}
+
+fn register(s: &str) -> Option<usize> {
+    match s.as_bytes() {
+        [b'r', n @ b'0'..=b'7'] => Some((n - b'0') as usize),
+        _ => None,
+    }
+}
+
+pub struct Debugger<'a, C, W> {
+    um: &'a mut UM<C>,
+    out: W,
+    breakpoints: BTreeSet<usize>,
+    /// Whether the program's last output byte was not a newline.
+    line_open: bool,
+}
+
+impl<'a, C: Console, W: Write> Debugger<'a, C, W> {
+    /// Creates a debugger for `um` that writes its own output to `out`.
+    pub fn new(um: &'a mut UM<C>, out: W) -> Self {
+        Debugger { um, out, breakpoints: BTreeSet::new(), line_open: false }
+    }
+
+    /// Executes commands until `quit` or the end of input.
+    ///
+    /// `read_line` appends the next command line like `BufRead::read_line`;
+    /// it is a function rather than a reader so that commands and program
+    /// input can both come from stdin without holding its lock. An empty
+    /// line repeats the previous command.
+    pub fn run(&mut self, mut read_line: impl FnMut(&mut String) -> io::Result<usize>) -> io::Result<()> {
+        self.show_location()?;
+        let mut last = String::new();
+        loop {
+            self.prepare_output()?;
+            write!(self.out, "(um) ")?;
+            self.out.flush()?;
+
+            let mut line = String::new();
+            if read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let line = match line.trim() {
+                "" => last.clone(),
+                line => line.to_string(),
+            };
+            if !self.execute(&line)? {
+                return Ok(());
+            }
+            last = line;
+        }
+    }
+
+    /// Executes one command, returning `false` if it asks to quit.
+    pub fn execute(&mut self, line: &str) -> io::Result<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result = match words.as_slice() {
+            [] => Ok(()),
+            ["q" | "quit"] => return Ok(false),
+            ["h" | "help"] => writeln!(self.out, "{}", HELP).map(Ok)?,
+            ["b" | "break", addr] => self.set_breakpoint(addr)?,
+            ["d" | "delete"] => {
+                self.breakpoints.clear();
+                Ok(())
+            }
+            ["d" | "delete", addr] => number(addr).and_then(|addr| {
+                match self.breakpoints.remove(&(addr as usize)) {
+                    true => Ok(()),
+                    false => Err(format!("no breakpoint at {:#x}", addr)),
+                }
+            }),
+            ["i" | "info"] => self.show_breakpoints().map(Ok)?,
+            ["s" | "step"] => self.resume(Some(1)).map(Ok)?,
+            ["s" | "step", n] => match number(n) {
+                Ok(n) => self.resume(Some(n as u64)).map(Ok)?,
+                Err(e) => Err(e),
+            },
+            ["c" | "continue"] => self.resume(None).map(Ok)?,
+            ["r" | "regs"] => self.show_registers().map(Ok)?,
+            ["l" | "list"] => self.list(5).map(Ok)?,
+            ["l" | "list", n] => match number(n) {
+                Ok(n) => self.list(n as usize).map(Ok)?,
+                Err(e) => Err(e),
+            },
+            [x, array, offset] if *x == "x" || x.starts_with("x/") => {
+                let count = match x.strip_prefix("x/") {
+                    Some(n) => number(n),
+                    None => Ok(8),
+                };
+                match (count, number(array), number(offset)) {
+                    (Ok(n), Ok(array), Ok(offset)) => self.dump(array, offset, n)?,
+                    (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => Err(e),
+                }
+            }
+            ["set", "finger", addr] => number(addr).map(|addr| self.um.set_finger(addr as usize)),
+            ["set", reg, value] => match (register(reg), number(value)) {
+                (Some(r), Ok(value)) => {
+                    self.um.registers_mut()[r] = value;
+                    Ok(())
+                }
+                (None, _) => Err(format!("unknown register {}", reg)),
+                (_, Err(e)) => Err(e),
+            },
+            ["set", array, offset, value] => match (number(array), number(offset), number(value)) {
+                (Ok(array), Ok(offset), Ok(value)) => match self.um.amend(array, offset, value) {
+                    true => Ok(()),
+                    false => Err(format!("array {} has no offset {:#x}", array, offset)),
+                },
+                (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => Err(e),
+            },
+            _ => Err(format!("unknown command {:?}; try help", line)),
+        };
+        if let Err(message) = result {
+            writeln!(self.out, "error: {}", message)?;
+        }
+        Ok(true)
+    }
+
+    fn set_breakpoint(&mut self, addr: &str) -> io::Result<Result<(), String>> {
+        let addr = match number(addr) {
+            Ok(addr) => addr as usize,
+            Err(e) => return Ok(Err(e)),
+        };
+        self.breakpoints.insert(addr);
+        writeln!(self.out, "breakpoint at {:08x}", addr)?;
+        Ok(Ok(()))
+    }
+
+    /// Runs until `steps` instructions have executed, or without limit when
+    /// `None`, stopping early at breakpoints, halts and faults.
+    fn resume(&mut self, steps: Option<u64>) -> io::Result<()> {
+        let mut executed = 0;
+        let stop = loop {
+            let outcome = self.um.step();
+            // The Input operator has not run yet; it is retried with the byte.
+            let retry = outcome == Outcome::NeedsInput;
+            match outcome {
+                Outcome::Output(byte) => self.line_open = byte != b'\n',
+                Outcome::NeedsInput => self.line_open = false,
+                _ => {}
+            }
+            match self.um.service(outcome)? {
+                Some(Outcome::Fault(fault)) => break Stop::Fault(fault),
+                Some(_) => break Stop::Halted,
+                None if retry => continue,
+                None => {}
+            }
+            executed += 1;
+            if Some(executed) == steps {
+                break Stop::Stepped;
+            }
+            if self.breakpoints.contains(&self.um.finger()) {
+                break Stop::Breakpoint;
+            }
+        };
+
+        self.prepare_output()?;
+        match stop {
+            Stop::Stepped => {}
+            Stop::Breakpoint => writeln!(self.out, "breakpoint at {:08x}", self.um.finger())?,
+            Stop::Halted => writeln!(self.out, "halted after {} cycles", self.um.cycles())?,
+            Stop::Fault(fault) => writeln!(self.out, "{}", fault)?,
+        }
+        self.show_location()
+    }
+
+    /// Flushes program output and ends a partial line.
+    fn prepare_output(&mut self) -> io::Result<()> {
+        self.um.console_mut().flush()?;
+        if self.line_open {
+            writeln!(self.out)?;
+            self.line_open = false;
+        }
+        Ok(())
+    }
+
+    fn show_location(&mut self) -> io::Result<()> {
+        self.list(1)
+    }
+
+    fn list(&mut self, count: usize) -> io::Result<()> {
+        let finger = self.um.finger();
+        let program = self.um.array(0).unwrap_or(&[]);
+        if finger >= program.len() {
+            return writeln!(self.out, "{:08x}: finger outside of array 0", finger);
+        }
+        for (addr, &p) in program.iter().enumerate().skip(finger).take(count) {
+            let mark = if self.breakpoints.contains(&addr) { '*' } else { ' ' };
+            writeln!(self.out, "{}{:08x}: {:08x}  {}", mark, addr, p, disasm::render(p))?;
+        }
+        Ok(())
+    }
+
+    fn show_breakpoints(&mut self) -> io::Result<()> {
+        if self.breakpoints.is_empty() {
+            return writeln!(self.out, "no breakpoints");
+        }
+        for addr in &self.breakpoints {
+            writeln!(self.out, "breakpoint at {:08x}", addr)?;
+        }
+        Ok(())
+    }
+
+    fn show_registers(&mut self) -> io::Result<()> {
+        let registers = *self.um.registers();
+        for (i, regs) in registers.chunks(4).enumerate() {
+            let line: Vec<String> = regs
+                .iter()
+                .enumerate()
+                .map(|(j, v)| format!("r{} = {:08x}", i * 4 + j, v))
+                .collect();
+            writeln!(self.out, "{}", line.join("  "))?;
+        }
+        writeln!(self.out, "finger = {:08x}  cycles = {}", self.um.finger(), self.um.cycles())
+    }
+
+    fn dump(&mut self, array: u32, offset: u32, count: u32) -> io::Result<Result<(), String>> {
+        let Some(platters) = self.um.array(array) else {
+            return Ok(Err(format!("array {} is not active", array)));
+        };
+        let start = (offset as usize).min(platters.len());
+        let end = start.saturating_add(count as usize).min(platters.len());
+        if start == end {
+            return Ok(Err(format!("array {} has no offset {:#x}", array, offset)));
+        }
+        for (row, chunk) in platters[start..end].chunks(4).enumerate() {
+            let words: Vec<String> = chunk.iter().map(|p| format!("{:08x}", p)).collect();
+            writeln!(self.out, "{}:{:08x}: {}", array, start + row * 4, words.join(" "))?;
+        }
+        Ok(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::IoConsole;
+    use crate::platter::{make_orthography, make_platter};
+    use std::io::BufRead;
+
+    fn session(program: Vec<u32>, commands: &str) -> (String, Vec<u8>) {
+        let mut um = UM::with_console(program, IoConsole::new(&b"z"[..], vec![]));
+        let mut out = vec![];
+        let mut commands = commands.as_bytes();
+        Debugger::new(&mut um, &mut out).run(|line| commands.read_line(line)).unwrap();
+        (String::from_utf8(out).unwrap(), um.into_console().into_inner().1)
+    }
+
+    fn hello() -> Vec<u32> {
+        vec![
+            make_orthography(1, 'H' as u32),
+            make_platter(10, 0, 0, 1),
+            make_orthography(1, 'i' as u32),
+            make_platter(10, 0, 0, 1),
+            make_platter(11, 0, 0, 2),
+            make_platter(10, 0, 0, 2),
+            make_platter(7, 0, 0, 0),
+        ]
+    }
+
+    #[test]
+    fn test_breakpoints_and_stepping() {
+        let (out, program_output) = session(hello(), "b 3\nc\nr\ns\n\ns\nc\nq\n");
+        assert_eq!(b"Hiz", program_output.as_slice());
+        assert!(out.starts_with(" 00000000: d2000048  ortho r1, 0x48"));
+        assert!(out.contains("breakpoint at 00000003\n*00000003: a0000001  out r1\n"));
+        assert!(out.contains("r0 = 00000000  r1 = 00000069  r2 = 00000000"));
+        assert!(out.contains("finger = 00000003  cycles = 3\n"));
+        // The empty line repeats `s`.
+        assert!(out.contains("(um)  00000005: a0000002  out r2\n"));
+        assert!(out.ends_with("halted after 6 cycles\n 00000006: 70000000  halt\n(um) "));
+    }
+
+    #[test]
+    fn test_editing_and_dumping() {
+        let (out, program_output) = session(hello(), "set r1 0x41\nset finger 1\nset 0 2 0x70000000\nx/4 0 1\nc\nx 9 0\n");
+        assert_eq!(b"A", program_output.as_slice());
+        assert!(out.contains("0:00000001: a0000001 70000000 a0000001 b0000002\n"));
+        assert!(out.contains("halted after 1 cycles"));
+        assert!(out.contains("error: array 9 is not active"));
+    }
+
+    #[test]
+    fn test_faults_are_reported() {
+        let (out, _) = session(vec![make_platter(5, 1, 2, 3)], "c\nfoo\n");
+        assert!(out.contains("division by zero"));
+        assert!(out.contains("error: unknown command \"foo\"; try help"));
+    }
+}
@@ -0,0 +1,74 @@
+//! Listing of UM images.
+
+use std::io;
+use std::io::Write;
+
+use crate::instruction::{DecodeError, Instruction};
+
+/// Renders an Orthography immediate as a character literal, if it is one.
+fn ascii(value: u32) -> Option<String> {
+    match value {
+        0x0a => Some("'\\n'".to_string()),
+        0x27 => Some("'\\''".to_string()),
+        0x5c => Some("'\\\\'".to_string()),
+        0x20..=0x7e => Some(format!("'{}'", value as u8 as char)),
+        _ => None,
+    }
+}
+
+/// Formats the text column for one platter.
+///
+/// Platters that do not decode, or that set register fields their operator
+/// ignores, are shown as `.word` data so the listing reassembles exactly.
+/// The comment names the instruction the machine runs, if any; it ignores
+/// reserved bits.
+pub fn render(p: u32) -> String {
+    match Instruction::decode(p) {
+        Ok(i @ Instruction::Orthography { value, .. }) => match ascii(value) {
+            Some(c) => format!("{:<24}; {}", i.to_string(), c),
+            None => i.to_string(),
+        },
+        Ok(i) if i.canonical() == i => i.to_string(),
+        Ok(i) => format!("{:<24}; {}", format!(".word {:#010x}", p), i.canonical()),
+        Err(DecodeError::ReservedBits(_)) => {
+            let i = Instruction::decode(p & 0xf000_01ff).unwrap();
+            format!("{:<24}; {} (reserved bits)", format!(".word {:#010x}", p), i.canonical())
+        }
+        Err(DecodeError::InvalidOperator(_)) => format!("{:<24}; data", format!(".word {:#010x}", p)),
+    }
+}
+
+/// Writes address, raw platter and text for each word of `image`.
+pub fn disassemble<W: Write>(image: &[u32], out: &mut W) -> io::Result<()> {
+    for (addr, &p) in image.iter().enumerate() {
+        writeln!(out, "{:08x}: {:08x}  {}", addr, p, render(p))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platter::{make_orthography, make_platter};
+
+    #[test]
+    fn test_disassemble() {
+        let image = [
+            make_orthography(1, 'H' as u32),
+            make_platter(10, 0, 0, 1),
+            make_platter(7, 0, 0, 1),
+            0xf000_0000,
+            0x3000_0251,
+        ];
+        let mut out = vec![];
+        disassemble(&image, &mut out).unwrap();
+        assert_eq!(
+            "00000000: d2000048  ortho r1, 0x48          ; 'H'\n\
+             00000001: a0000001  out r1\n\
+             00000002: 70000001  .word 0x70000001        ; halt\n\
+             00000003: f0000000  .word 0xf0000000        ; data\n\
+             00000004: 30000251  .word 0x30000251        ; add r1, r2, r1 (reserved bits)\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+}
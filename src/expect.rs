@@ -0,0 +1,375 @@
+//! Expect-style automation of console sessions.
+//!
+//! A `Script` is a list of rules, each waiting for the machine's output to
+//! match a pattern and then sending some input. Rules are met in order, each
+//! searching the output after the previous match, and input they send is
+//! read by the program before anything from the console. A rule may have a
+//! timeout, counted in cycles from when the previous rule was met.
+//!
+//! Rule files have one directive per line; blank lines and lines starting
+//! with `#` are ignored:
+//!
+//! ```text
+//! timeout 50000000        # for the rules below; `timeout none` removes it
+//! expect login: $
+//! send guest\n
+//! expect % $
+//! send ls /home\n
+//! ```
+//!
+//! `expect` takes a regular expression, everything after the space. `send`
+//! takes text with the escapes `\n`, `\r`, `\t`, `\\` and `\xNN`, and is the
+//! input of the `expect` before it; a `send` on its own is sent as soon as
+//! the rules before it are met.
+//!
+//! Only a trailing window of the output, at least `WINDOW` bytes, is kept
+//! for the patterns, so longer matches may be missed.
+
+use std::collections::VecDeque;
+use std::error::Error as StdError;
+use std::fmt;
+
+use regex::bytes::Regex;
+
+use crate::console::Console;
+use crate::fault::Error;
+use crate::um::{Outcome, UM};
+
+/// Output kept for matching, in bytes.
+pub const WINDOW: usize = 1 << 16;
+
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub pattern: Regex,
+    pub send: Vec<u8>,
+    /// Cycles allowed for the pattern to appear; `None` waits forever.
+    pub timeout: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Script {
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptError {
+    /// 1-based line in the rule file.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl StdError for ScriptError {}
+
+/// Decodes the escapes of a `send` directive.
+fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('t') => bytes.push(b'\t'),
+            Some('\\') => bytes.push(b'\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let byte = u8::from_str_radix(&hex, 16).map_err(|_| format!("bad escape \\x{}", hex))?;
+                bytes.push(byte);
+            }
+            Some(c) => return Err(format!("unknown escape \\{}", c)),
+            None => return Err("escape at end of line".to_string()),
+        }
+    }
+    Ok(bytes)
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Script::default()
+    }
+
+    /// Adds a rule sending `send` once `pattern` matches.
+    pub fn rule(mut self, pattern: &str, send: &[u8], timeout: Option<u64>) -> Result<Self, regex::Error> {
+        let pattern = Regex::new(pattern)?;
+        self.rules.push(Rule { pattern, send: send.to_vec(), timeout });
+        Ok(self)
+    }
+
+    /// Parses a rule file.
+    pub fn parse(text: &str) -> Result<Self, ScriptError> {
+        let mut script = Script::new();
+        let mut timeout = None;
+        // Whether the last rule came from an `expect` still without input.
+        let mut open = false;
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: String| ScriptError { line: i + 1, message };
+            if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                continue;
+            }
+            let (directive, arg) = line.split_once(' ').unwrap_or((line, ""));
+            match directive {
+                "timeout" => {
+                    timeout = match arg.split('#').next().unwrap_or("").trim() {
+                        "none" => None,
+                        n => Some(n.parse().map_err(|_| error(format!("bad timeout {:?}", n)))?),
+                    };
+                }
+                "expect" => {
+                    script = script.rule(arg, b"", timeout).map_err(|e| error(e.to_string()))?;
+                    open = true;
+                }
+                "send" => {
+                    let send = unescape(arg).map_err(error)?;
+                    match script.rules.last_mut() {
+                        Some(rule) if open => rule.send = send,
+                        _ => script = script.rule("", &send, timeout).unwrap(),
+                    }
+                    open = false;
+                }
+                _ => return Err(error(format!("unknown directive {:?}", directive))),
+            }
+        }
+        Ok(script)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    TimedOut,
+    Halted,
+}
+
+/// A rule that was not met, and why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unmet {
+    /// Index of the rule in the script.
+    pub rule: usize,
+    pub pattern: String,
+    pub cycle: u64,
+    pub reason: Reason,
+}
+
+impl fmt::Display for Unmet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self.reason {
+            Reason::TimedOut => "timed out",
+            Reason::Halted => "the machine halted",
+        };
+        write!(f, "rule {} (expect {:?}) not met: {} at cycle {}", self.rule + 1, self.pattern, reason, self.cycle)
+    }
+}
+
+/// Progress through a script.
+struct Session<'a> {
+    script: &'a Script,
+    next: usize,
+    /// Output since the last match, cut to `WINDOW` bytes when it grows
+    /// to twice that.
+    output: Vec<u8>,
+    input: VecDeque<u8>,
+    deadline: Option<u64>,
+}
+
+impl<'a> Session<'a> {
+    fn new(script: &'a Script, cycles: u64) -> Self {
+        let mut session = Session { script, next: 0, output: vec![], input: VecDeque::new(), deadline: None };
+        session.start(cycles);
+        session
+    }
+
+    fn start(&mut self, cycles: u64) {
+        let timeout = self.script.rules.get(self.next).and_then(|rule| rule.timeout);
+        self.deadline = timeout.map(|n| cycles.saturating_add(n));
+    }
+
+    fn push(&mut self, byte: u8) {
+        if self.next == self.script.rules.len() {
+            return;
+        }
+        if self.output.len() == 2 * WINDOW {
+            self.output.drain(..WINDOW);
+        }
+        self.output.push(byte);
+    }
+
+    /// Meets as many rules as the output allows.
+    fn advance(&mut self, cycles: u64) {
+        while let Some(rule) = self.script.rules.get(self.next) {
+            let Some(found) = rule.pattern.find(&self.output) else {
+                return;
+            };
+            self.output.drain(..found.end());
+            self.input.extend(&rule.send);
+            self.next += 1;
+            self.start(cycles);
+        }
+    }
+
+    fn unmet(&self, cycle: u64, reason: Reason) -> Option<Unmet> {
+        let rule = self.script.rules.get(self.next)?;
+        Some(Unmet { rule: self.next, pattern: rule.pattern.to_string(), cycle, reason })
+    }
+}
+
+/// Runs `um` to a halt, feeding it the input of the rules in `script` as
+/// their patterns appear in the output. When no such input is
+/// queued, the program reads from the console.
+///
+/// Returns the first rule that was not met, if any; the machine stops there.
+pub fn run_expect<C: Console>(um: &mut UM<C>, script: &Script) -> Result<Option<Unmet>, Error> {
+    let result = drive(um, script);
+    um.console_mut().flush()?;
+    result
+}
+
+fn drive<C: Console>(um: &mut UM<C>, script: &Script) -> Result<Option<Unmet>, Error> {
+    let mut session = Session::new(script, um.cycles());
+    loop {
+        let budget = match session.deadline {
+            Some(deadline) => um.until_flush().min(deadline.saturating_sub(um.cycles()).max(1)),
+            None => um.until_flush(),
+        };
+        let outcome = um.run_until(budget);
+        match outcome {
+            Outcome::Output(byte) => session.push(byte),
+            Outcome::NeedsInput | Outcome::BudgetExhausted => session.advance(um.cycles()),
+            _ => {}
+        }
+        let stop = match outcome {
+            Outcome::NeedsInput if !session.input.is_empty() => {
+                um.answer_input(session.input.pop_front());
+                um.flush_if_due()?;
+                None
+            }
+            outcome => um.service(outcome)?,
+        };
+        match stop {
+            Some(Outcome::Fault(fault)) => return Err(fault.into()),
+            Some(_) => {
+                session.advance(um.cycles());
+                return Ok(session.unmet(um.cycles(), Reason::Halted));
+            }
+            None => {}
+        }
+        if session.deadline.is_some_and(|deadline| um.cycles() >= deadline) {
+            session.advance(um.cycles());
+            if session.deadline.is_some_and(|deadline| um.cycles() >= deadline) {
+                return Ok(session.unmet(um.cycles(), Reason::TimedOut));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::console::IoConsole;
+
+    /// Twice prompts with `? ` and echoes four bytes of input.
+    fn prompter() -> Vec<u32> {
+        assemble(
+            "
+            .macro prompt
+                    ortho r1, '?'
+                    out r1
+                    ortho r1, ' '
+                    out r1
+            .endm
+            .macro echo
+                    in r2
+                    out r2
+            .endm
+                    prompt
+                    echo
+                    echo
+                    echo
+                    echo
+                    prompt
+                    echo
+                    echo
+                    echo
+                    echo
+                    halt
+            ",
+        )
+        .unwrap()
+    }
+
+    fn run(program: Vec<u32>, script: &Script, input: &[u8]) -> (Option<Unmet>, Vec<u8>) {
+        let mut um = UM::with_console(program, IoConsole::new(input, vec![]));
+        let unmet = run_expect(&mut um, script).unwrap();
+        (unmet, um.into_console().into_inner().1)
+    }
+
+    #[test]
+    fn test_parse() {
+        let script = Script::parse("# log in\ntimeout 100\nexpect ^login: $\nsend guest\\n\n\ntimeout none\nsend \\x41\\\\\n")
+            .unwrap();
+        assert_eq!(2, script.rules.len());
+        assert_eq!("^login: $", script.rules[0].pattern.as_str());
+        assert_eq!((b"guest\n".to_vec(), Some(100)), (script.rules[0].send.clone(), script.rules[0].timeout));
+        assert_eq!((b"A\\".to_vec(), None), (script.rules[1].send.clone(), script.rules[1].timeout));
+
+        let error = Script::parse("expect a\nsend \\q").unwrap_err();
+        assert_eq!("line 2: unknown escape \\q", error.to_string());
+        assert_eq!(1, Script::parse("expect (").unwrap_err().line);
+        assert_eq!(1, Script::parse("wait 10").unwrap_err().line);
+    }
+
+    #[test]
+    fn test_rules_in_order() {
+        let script = Script::parse("expect \\? $\nsend ann\\n\nexpect \\? $\nsend bob\\n\n").unwrap();
+        let (unmet, output) = run(prompter(), &script, b"");
+        assert_eq!(None, unmet);
+        assert_eq!(b"? ann\n? bob\n", output.as_slice());
+
+        // The console takes over once the script's input is used up.
+        let script = Script::new().rule("\\? $", b"ann\n", None).unwrap();
+        let (unmet, output) = run(prompter(), &script, b"cyd\n");
+        assert_eq!(None, unmet);
+        assert_eq!(b"? ann\n? cyd\n", output.as_slice());
+    }
+
+    #[test]
+    fn test_output_window() {
+        let script = Script::new().rule("x", b"", None).unwrap();
+        let mut session = Session::new(&script, 0);
+        for _ in 0..3 * WINDOW {
+            session.push(b'.');
+        }
+        assert!(session.output.len() <= 2 * WINDOW);
+        session.push(b'x');
+        session.advance(0);
+        assert_eq!(1, session.next);
+
+        // Nothing is kept once every rule is met.
+        session.output.clear();
+        session.push(b'.');
+        assert!(session.output.is_empty());
+    }
+
+    #[test]
+    fn test_unmet_rules() {
+        let script = Script::new().rule("login:", b"", Some(1000)).unwrap();
+        let (unmet, _) = run(prompter(), &script, b"ann\nbob\n");
+        assert_eq!(Some(Reason::Halted), unmet.map(|u| u.reason));
+
+        // A program that loops forever.
+        let script = Script::new().rule("login:", b"", Some(1000)).unwrap();
+        let (unmet, _) = run(vec![crate::platter::make_platter(12, 0, 0, 0)], &script, b"");
+        let unmet = unmet.unwrap();
+        assert_eq!((0, 1000, Reason::TimedOut), (unmet.rule, unmet.cycle, unmet.reason));
+        assert_eq!("rule 1 (expect \"login:\") not met: timed out at cycle 1000", unmet.to_string());
+    }
+}
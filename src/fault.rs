@@ -0,0 +1,94 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+
+/// A machine failure as defined by the specification.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fault {
+    pub finger: usize,
+    pub platter: u32,
+    pub registers: [u32; 8],
+    pub kind: FaultKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultKind {
+    /// The finger does not point into array 0.
+    FingerOutOfBounds,
+    /// The operator number is not one of the fourteen defined operators.
+    InvalidOperator(u8),
+    /// An array identifier that is not currently allocated.
+    InactiveArray(u32),
+    /// An offset past the end of an active array.
+    OffsetOutOfBounds { array: u32, offset: u32 },
+    DivisionByZero,
+    /// Abandonment of array 0.
+    AbandonedProgram,
+    /// Output was asked to emit a value greater than 255.
+    OutputOutOfRange(u32),
+}
+
+impl fmt::Display for FaultKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FaultKind::FingerOutOfBounds => write!(f, "finger outside of array 0"),
+            FaultKind::InvalidOperator(op) => write!(f, "invalid operator {}", op),
+            FaultKind::InactiveArray(id) => write!(f, "array {} is not active", id),
+            FaultKind::OffsetOutOfBounds { array, offset } => {
+                write!(f, "offset {} out of bounds for array {}", offset, array)
+            }
+            FaultKind::DivisionByZero => write!(f, "division by zero"),
+            FaultKind::AbandonedProgram => write!(f, "abandonment of array 0"),
+            FaultKind::OutputOutOfRange(v) => write!(f, "output value {} out of range", v),
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at finger {} (platter {:#010x}), registers {:?}",
+            self.kind, self.finger, self.platter, self.registers
+        )
+    }
+}
+
+impl StdError for Fault {}
+
+/// Why `UM::spin_cycle` stopped before the machine halted.
+#[derive(Debug)]
+pub enum Error {
+    Fault(Fault),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Fault(fault) => write!(f, "machine failure: {}", fault),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Fault(fault) => Some(fault),
+            Error::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<Fault> for Error {
+    fn from(fault: Fault) -> Self {
+        Error::Fault(fault)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
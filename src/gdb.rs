@@ -0,0 +1,463 @@
+//! GDB remote serial protocol stub.
+//!
+//! The stub presents the machine as a 32-bit big-endian target with nine
+//! registers, `r0` to `r7` and `pc`, where `pc` is the finger times four.
+//! Memory is byte addressed: the low 32 bits of an address are a byte offset
+//! into an array and the high 32 bits its identifier, so array 0 sits at the
+//! bottom of the address space and array 5 starts at `0x500000000`. Platters
+//! are stored big-endian, as in program images; use `set endian big` in GDB.
+//!
+//! Breakpoints, single steps and interrupts are handled by the stub's own
+//! loop around `UM::step`, so `spin_cycle` pays nothing for them.
+
+use std::collections::BTreeSet;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+use crate::console::Console;
+use crate::fault::{Fault, FaultKind};
+use crate::um::{Outcome, UM};
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.icfp2006.um">
+    <reg name="r0" bitsize="32" type="uint32"/>
+    <reg name="r1" bitsize="32" type="uint32"/>
+    <reg name="r2" bitsize="32" type="uint32"/>
+    <reg name="r3" bitsize="32" type="uint32"/>
+    <reg name="r4" bitsize="32" type="uint32"/>
+    <reg name="r5" bitsize="32" type="uint32"/>
+    <reg name="r6" bitsize="32" type="uint32"/>
+    <reg name="r7" bitsize="32" type="uint32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// Instructions executed between checks for an interrupt from GDB.
+const POLL_INTERVAL: u64 = 1 << 16;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+/// How a debugging session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum End {
+    /// GDB detached; the machine can keep running on its own.
+    Detached,
+    /// GDB killed the program or went away.
+    Killed,
+    /// The machine halted.
+    Exited,
+}
+
+fn signal(kind: FaultKind) -> u8 {
+    match kind {
+        FaultKind::InvalidOperator(_) => SIGILL,
+        FaultKind::DivisionByZero => SIGFPE,
+        _ => SIGSEGV,
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parses `addr,len` as sent with memory and breakpoint packets.
+fn address_and_length(s: &str) -> Option<(u64, usize)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((u64::from_str_radix(addr, 16).ok()?, usize::from_str_radix(len, 16).ok()?))
+}
+
+pub struct GdbStub<'a, C> {
+    um: &'a mut UM<C>,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    breakpoints: BTreeSet<usize>,
+    ack: bool,
+}
+
+impl<'a, C: Console> GdbStub<'a, C> {
+    /// Creates a stub serving `um` to the GDB connected on `stream`.
+    pub fn new(um: &'a mut UM<C>, stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Ok(GdbStub {
+            um,
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            breakpoints: BTreeSet::new(),
+            ack: true,
+        })
+    }
+
+    /// Answers packets until GDB detaches or kills the program, or the
+    /// machine halts.
+    pub fn serve(&mut self) -> io::Result<End> {
+        loop {
+            let Some(packet) = self.receive()? else {
+                return Ok(End::Killed);
+            };
+            let reply = match packet.as_str() {
+                "D" => {
+                    self.send("OK")?;
+                    return Ok(End::Detached);
+                }
+                "k" => return Ok(End::Killed),
+                "c" => self.resume(false)?,
+                "s" => self.resume(true)?,
+                _ => self.answer(&packet),
+            };
+            self.send(&reply)?;
+            if reply.starts_with('W') {
+                return Ok(End::Exited);
+            }
+        }
+    }
+
+    /// Replies to packets that do not run the machine.
+    fn answer(&mut self, packet: &str) -> String {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => Some(format!("S{:02x}", SIGTRAP)),
+            Some(b'g') => Some(self.read_registers()),
+            Some(b'G') => self.write_registers(&packet[1..]),
+            Some(b'p') => self.read_register(&packet[1..]),
+            Some(b'P') => self.write_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b'Z' | b'z') => return self.breakpoint(packet),
+            Some(b'H') => Some("OK".to_string()),
+            Some(b'q' | b'Q') => return self.query(packet),
+            _ => return String::new(),
+        };
+        reply.unwrap_or_else(|| "E01".to_string())
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".to_string();
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, len)) = address_and_length(range) else {
+                return "E01".to_string();
+            };
+            let start = (offset as usize).min(TARGET_XML.len());
+            let end = start.saturating_add(len).min(TARGET_XML.len());
+            let more = if end < TARGET_XML.len() { 'm' } else { 'l' };
+            return format!("{}{}", more, &TARGET_XML[start..end]);
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                self.ack = false;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn breakpoint(&mut self, packet: &str) -> String {
+        // Software and hardware breakpoints are the same thing here.
+        let (insert, rest) = match packet.get(..3) {
+            Some("Z0," | "Z1,") => (true, &packet[3..]),
+            Some("z0," | "z1,") => (false, &packet[3..]),
+            _ => return String::new(),
+        };
+        let Some((addr, _)) = address_and_length(rest) else {
+            return "E01".to_string();
+        };
+        let finger = (addr / 4) as usize;
+        if insert {
+            self.breakpoints.insert(finger);
+        } else {
+            self.breakpoints.remove(&finger);
+        }
+        "OK".to_string()
+    }
+
+    fn read_registers(&self) -> String {
+        let mut bytes = vec![];
+        for r in self.um.registers() {
+            bytes.extend(r.to_be_bytes());
+        }
+        bytes.extend(((self.um.finger() * 4) as u32).to_be_bytes());
+        hex(&bytes)
+    }
+
+    fn write_registers(&mut self, data: &str) -> Option<String> {
+        let bytes = unhex(data)?;
+        if bytes.len() != 36 {
+            return None;
+        }
+        for (i, chunk) in bytes.chunks(4).enumerate() {
+            self.set_register(i, u32::from_be_bytes(chunk.try_into().unwrap()));
+        }
+        Some("OK".to_string())
+    }
+
+    fn read_register(&self, n: &str) -> Option<String> {
+        let value = match usize::from_str_radix(n, 16).ok()? {
+            n @ 0..=7 => self.um.registers()[n],
+            8 => (self.um.finger() * 4) as u32,
+            _ => return None,
+        };
+        Some(hex(&value.to_be_bytes()))
+    }
+
+    fn write_register(&mut self, assignment: &str) -> Option<String> {
+        let (n, value) = assignment.split_once('=')?;
+        let n = usize::from_str_radix(n, 16).ok().filter(|&n| n <= 8)?;
+        let value = unhex(value)?;
+        self.set_register(n, u32::from_be_bytes(value.try_into().ok()?));
+        Some("OK".to_string())
+    }
+
+    fn set_register(&mut self, n: usize, value: u32) {
+        match n {
+            8 => self.um.set_finger(value as usize / 4),
+            n => self.um.registers_mut()[n] = value,
+        }
+    }
+
+    fn read_memory(&self, range: &str) -> Option<String> {
+        let (addr, len) = address_and_length(range)?;
+        let platters = self.um.array((addr >> 32) as u32)?;
+        let start = addr as u32 as usize;
+        let bytes = (start..start.checked_add(len)?)
+            .map(|i| platters.get(i / 4).map(|p| p.to_be_bytes()[i % 4]))
+            .collect::<Option<Vec<u8>>>()?;
+        Some(hex(&bytes))
+    }
+
+    fn write_memory(&mut self, packet: &str) -> Option<String> {
+        let (range, data) = packet.split_once(':')?;
+        let (addr, len) = address_and_length(range)?;
+        let data = unhex(data).filter(|d| d.len() == len)?;
+        let id = (addr >> 32) as u32;
+        let start = addr as u32 as usize;
+        let platters = self.um.array(id)?;
+        if start.checked_add(len)? > platters.len() * 4 {
+            return None;
+        }
+        // Amend whole platters, merging in the bytes that change.
+        let mut changed: Vec<(u32, [u8; 4])> = vec![];
+        for (i, byte) in (start..).zip(data) {
+            let offset = (i / 4) as u32;
+            if changed.last().map(|c| c.0) != Some(offset) {
+                changed.push((offset, platters[i / 4].to_be_bytes()));
+            }
+            changed.last_mut().unwrap().1[i % 4] = byte;
+        }
+        for (offset, bytes) in changed {
+            self.um.amend(id, offset, u32::from_be_bytes(bytes));
+        }
+        Some("OK".to_string())
+    }
+
+    /// Runs one instruction, or until a breakpoint, a fault, a halt or an
+    /// interrupt, and returns the stop reply.
+    fn resume(&mut self, single: bool) -> io::Result<String> {
+        let mut executed: u64 = 0;
+        let reply = loop {
+            let outcome = self.um.step();
+            let retry = outcome == Outcome::NeedsInput;
+            match self.um.service(outcome)? {
+                Some(Outcome::Fault(Fault { kind, .. })) => break format!("S{:02x}", signal(kind)),
+                Some(_) => break "W00".to_string(),
+                None if retry => continue,
+                None => {}
+            }
+            if single || self.breakpoints.contains(&self.um.finger()) {
+                break format!("S{:02x}", SIGTRAP);
+            }
+            executed += 1;
+            if executed.is_multiple_of(POLL_INTERVAL) && self.interrupted()? {
+                break format!("S{:02x}", SIGINT);
+            }
+        };
+        self.um.console_mut().flush()?;
+        Ok(reply)
+    }
+
+    /// Checks, without blocking, whether GDB has sent an interrupt.
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.reader.get_ref().set_nonblocking(true)?;
+        let available = match self.reader.fill_buf() {
+            Ok(buf) => buf.to_vec(),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => vec![],
+            Err(e) => return Err(e),
+        };
+        self.reader.get_ref().set_nonblocking(false)?;
+        match available.first() {
+            Some(0x03) => {
+                self.reader.consume(1);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.reader.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// Reads the next packet, acknowledging it; `None` when GDB disconnects.
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Skip acknowledgements and interrupts that arrive while stopped.
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(_) => continue,
+            }
+            let mut data = vec![];
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                }
+            }
+            let mut sum = [0; 2];
+            self.reader.read_exact(&mut sum)?;
+            let valid = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok())
+                == Some(checksum(&data));
+            if self.ack {
+                self.writer.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        loop {
+            self.writer.write_all(packet.as_bytes())?;
+            if !self.ack {
+                return Ok(());
+            }
+            match self.read_byte()? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::IoConsole;
+    use crate::platter::{make_orthography, make_platter};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Sends each packet in turn and collects the replies.
+    fn client(stream: TcpStream, packets: &[&str]) -> Vec<String> {
+        stream.set_nodelay(true).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut replies = vec![];
+        for p in packets {
+            write!(writer, "${}#{:02x}", p, checksum(p.as_bytes())).unwrap();
+            let mut reply = vec![];
+            reader.read_until(b'#', &mut reply).unwrap();
+            if reply.last() != Some(&b'#') {
+                break; // The stub closed the connection.
+            }
+            let mut sum = [0; 2];
+            reader.read_exact(&mut sum).unwrap();
+            writer.write_all(b"+").unwrap();
+            let start = reply.iter().position(|&b| b == b'$').unwrap();
+            replies.push(String::from_utf8(reply[start + 1..reply.len() - 1].to_vec()).unwrap());
+        }
+        replies
+    }
+
+    fn session(program: Vec<u32>, packets: &'static [&'static str]) -> (End, Vec<String>, Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let gdb = thread::spawn(move || client(TcpStream::connect(addr).unwrap(), packets));
+
+        let mut um = UM::with_console(program, IoConsole::new(&b""[..], vec![]));
+        let (stream, _) = listener.accept().unwrap();
+        let end = GdbStub::new(&mut um, stream).unwrap().serve().unwrap();
+        (end, gdb.join().unwrap(), um.into_console().into_inner().1)
+    }
+
+    fn program() -> Vec<u32> {
+        vec![
+            make_orthography(1, 'A' as u32),
+            make_platter(10, 0, 0, 1),
+            make_orthography(1, 'B' as u32),
+            make_platter(10, 0, 0, 1),
+            make_platter(7, 0, 0, 0),
+        ]
+    }
+
+    #[test]
+    fn test_breakpoints_and_registers() {
+        let (end, replies, output) = session(
+            program(),
+            &["qSupported:xmlRegisters=i386", "?", "Z0,c,1", "c", "g", "p8", "s", "z0,c,1", "c"],
+        );
+        assert_eq!(End::Exited, end);
+        assert_eq!(b"AB", output.as_slice());
+        assert!(replies[0].contains("qXfer:features:read+"));
+        assert_eq!("S05", replies[1]);
+        assert_eq!(["OK", "S05"], replies[2..4]);
+        assert_eq!(format!("00000000{}{}0000000c", "00000042", "00000000".repeat(6)), replies[4]);
+        assert_eq!("0000000c", replies[5]);
+        assert_eq!(["S05", "OK", "W00"], replies[6..]);
+    }
+
+    #[test]
+    fn test_memory_and_arrays() {
+        let mut program = program();
+        program.splice(0..0, [make_orthography(2, 3), make_platter(8, 0, 3, 2)]);
+        let (end, replies, output) = session(
+            program,
+            &["s", "s", "m0,8", "M9,2:0058", "m8,4", "M100000004,4:cafef00d", "m100000000,c", "m100000000,10", "k"],
+        );
+        assert_eq!(End::Killed, end);
+        assert!(output.is_empty());
+        assert_eq!("d40000038000001a", replies[2]);
+        assert_eq!(["OK", "d2005841"], replies[3..5]);
+        assert_eq!(["OK", "00000000cafef00d00000000", "E01"], replies[5..]);
+    }
+
+    #[test]
+    fn test_faults_report_signals() {
+        let (_, replies, _) = session(vec![make_platter(5, 1, 2, 3), make_platter(7, 0, 0, 0)], &["c", "P3=00000001", "c"]);
+        assert_eq!(["S08", "OK", "W00"], replies[..]);
+    }
+}
@@ -0,0 +1,216 @@
+//! A typed view of the fourteen operators.
+
+use std::error::Error;
+use std::fmt;
+
+use crate::platter::*;
+
+/// A decoded instruction.
+///
+/// Standard operators keep all three register fields, including the ones the
+/// operator ignores, so that `Instruction::decode(p)?.encode() == p` holds for
+/// every platter that decodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    ConditionalMove { a: u8, b: u8, c: u8 },
+    ArrayIndex { a: u8, b: u8, c: u8 },
+    ArrayAmendment { a: u8, b: u8, c: u8 },
+    Addition { a: u8, b: u8, c: u8 },
+    Multiplication { a: u8, b: u8, c: u8 },
+    Division { a: u8, b: u8, c: u8 },
+    NotAnd { a: u8, b: u8, c: u8 },
+    Halt { a: u8, b: u8, c: u8 },
+    Allocation { a: u8, b: u8, c: u8 },
+    Abandonment { a: u8, b: u8, c: u8 },
+    Output { a: u8, b: u8, c: u8 },
+    Input { a: u8, b: u8, c: u8 },
+    LoadProgram { a: u8, b: u8, c: u8 },
+    Orthography { a: u8, value: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Operator numbers 14 and 15 are not defined.
+    InvalidOperator(u8),
+    /// A standard operator with bits set between the operator and register fields.
+    ReservedBits(u32),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::InvalidOperator(op) => write!(f, "invalid operator {}", op),
+            DecodeError::ReservedBits(p) => write!(f, "reserved bits set in {:#010x}", p),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+impl Instruction {
+    pub fn decode(p: u32) -> Result<Instruction, DecodeError> {
+        use Instruction::*;
+
+        let op = op_code(p);
+        if op == 13 {
+            return Ok(Orthography { a: rego_offset(p) as u8, value: rego_value(p) });
+        }
+        if op > 13 {
+            return Err(DecodeError::InvalidOperator(op));
+        }
+        if p & 0x0fff_fe00 != 0 {
+            return Err(DecodeError::ReservedBits(p));
+        }
+
+        let a = rega_offset(p) as u8;
+        let b = regb_offset(p) as u8;
+        let c = regc_offset(p) as u8;
+        Ok(match op {
+            0 => ConditionalMove { a, b, c },
+            1 => ArrayIndex { a, b, c },
+            2 => ArrayAmendment { a, b, c },
+            3 => Addition { a, b, c },
+            4 => Multiplication { a, b, c },
+            5 => Division { a, b, c },
+            6 => NotAnd { a, b, c },
+            7 => Halt { a, b, c },
+            8 => Allocation { a, b, c },
+            9 => Abandonment { a, b, c },
+            10 => Output { a, b, c },
+            11 => Input { a, b, c },
+            _ => LoadProgram { a, b, c },
+        })
+    }
+
+    /// Encodes the instruction. Register numbers are taken modulo 8 and the
+    /// Orthography value modulo 2^25.
+    pub fn encode(&self) -> u32 {
+        use Instruction::*;
+
+        let standard = |op: u32, a: u8, b: u8, c: u8| {
+            (op << 28) | ((a as u32 & 7) << 6) | ((b as u32 & 7) << 3) | (c as u32 & 7)
+        };
+        match *self {
+            ConditionalMove { a, b, c } => standard(0, a, b, c),
+            ArrayIndex { a, b, c } => standard(1, a, b, c),
+            ArrayAmendment { a, b, c } => standard(2, a, b, c),
+            Addition { a, b, c } => standard(3, a, b, c),
+            Multiplication { a, b, c } => standard(4, a, b, c),
+            Division { a, b, c } => standard(5, a, b, c),
+            NotAnd { a, b, c } => standard(6, a, b, c),
+            Halt { a, b, c } => standard(7, a, b, c),
+            Allocation { a, b, c } => standard(8, a, b, c),
+            Abandonment { a, b, c } => standard(9, a, b, c),
+            Output { a, b, c } => standard(10, a, b, c),
+            Input { a, b, c } => standard(11, a, b, c),
+            LoadProgram { a, b, c } => standard(12, a, b, c),
+            Orthography { a, value } => (13 << 28) | ((a as u32 & 7) << 25) | (value & 0x1ff_ffff),
+        }
+    }
+
+    /// The operator number.
+    pub fn op_code(&self) -> u8 {
+        (self.encode() >> 28) as u8
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        MNEMONICS[self.op_code() as usize]
+    }
+
+    /// The same instruction with the register fields the operator ignores
+    /// cleared. This is what the textual form describes.
+    pub fn canonical(&self) -> Instruction {
+        use Instruction::*;
+
+        match *self {
+            Halt { .. } => Halt { a: 0, b: 0, c: 0 },
+            Allocation { b, c, .. } => Allocation { a: 0, b, c },
+            Abandonment { c, .. } => Abandonment { a: 0, b: 0, c },
+            Output { c, .. } => Output { a: 0, b: 0, c },
+            Input { c, .. } => Input { a: 0, b: 0, c },
+            LoadProgram { b, c, .. } => LoadProgram { a: 0, b, c },
+            i => i,
+        }
+    }
+}
+
+/// Assembly mnemonics, indexed by operator number.
+pub const MNEMONICS: [&str; 14] = [
+    "cmov", "index", "amend", "add", "mul", "div", "nand",
+    "halt", "alloc", "free", "out", "in", "load", "ortho",
+];
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Instruction::*;
+
+        let name = self.mnemonic();
+        match *self {
+            ConditionalMove { a, b, c }
+            | ArrayIndex { a, b, c }
+            | ArrayAmendment { a, b, c }
+            | Addition { a, b, c }
+            | Multiplication { a, b, c }
+            | Division { a, b, c }
+            | NotAnd { a, b, c } => write!(f, "{} r{}, r{}, r{}", name, a, b, c),
+            Halt { .. } => write!(f, "{}", name),
+            Allocation { b, c, .. } | LoadProgram { b, c, .. } => write!(f, "{} r{}, r{}", name, b, c),
+            Abandonment { c, .. } | Output { c, .. } | Input { c, .. } => write!(f, "{} r{}", name, c),
+            Orthography { a, value } => write!(f, "{} r{}, {:#x}", name, a, value),
+        }
+    }
+}
+
+impl TryFrom<u32> for Instruction {
+    type Error = DecodeError;
+
+    fn try_from(p: u32) -> Result<Self, Self::Error> {
+        Instruction::decode(p)
+    }
+}
+
+impl From<Instruction> for u32 {
+    fn from(i: Instruction) -> u32 {
+        i.encode()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            Ok(Instruction::Addition { a: 1, b: 2, c: 3 }),
+            Instruction::decode(make_platter(3, 1, 2, 3))
+        );
+        assert_eq!(
+            Ok(Instruction::Orthography { a: 7, value: 0x41 }),
+            Instruction::decode(make_orthography(7, 0x41))
+        );
+        assert_eq!(Err(DecodeError::InvalidOperator(14)), Instruction::decode(0xe000_0000));
+        assert_eq!(Err(DecodeError::ReservedBits(0x7000_0200)), Instruction::decode(0x7000_0200));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!("nand r1, r2, r3", Instruction::NotAnd { a: 1, b: 2, c: 3 }.to_string());
+        assert_eq!("alloc r2, r3", Instruction::Allocation { a: 0, b: 2, c: 3 }.to_string());
+        assert_eq!("ortho r0, 0x41", Instruction::Orthography { a: 0, value: 0x41 }.to_string());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut p: u32 = 1;
+        for _ in 0..100_000 {
+            p = p.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            for platter in [p, p & 0xf000_01ff] {
+                if let Ok(i) = Instruction::decode(platter) {
+                    assert_eq!(platter, i.encode());
+                    assert_eq!(Ok(i), Instruction::decode(i.encode()));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,443 @@
+//! Native compilation of basic blocks in array 0.
+//!
+//! A block is a run of operators that neither branch, allocate nor do I/O:
+//! Conditional Move, Array Index, Array Amendment, Addition, Multiplication,
+//! Division, Nand and Orthography. Once the interpreter has entered a block
+//! often enough it is compiled with Cranelift; everything else, including
+//! the instruction that ends the block, is still executed by `UM::step`.
+//!
+//! Compiled code reaches the arrays through `index` and `amend`. A block
+//! stops before an Amendment of array 0, which the interpreter executes so
+//! that the decoded program and the blocks it overlaps are brought up to
+//! date; Load Program drops all blocks.
+
+use std::fmt;
+
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, MemFlags, Signature, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+
+use crate::array::Array;
+use crate::code::Op;
+
+/// Entries needed before a block is compiled.
+const HOT: u32 = 16;
+/// Shortest block worth compiling.
+const MIN_BLOCK: usize = 2;
+/// Longest block; also bounds the search when an amendment invalidates.
+const MAX_BLOCK: usize = 256;
+/// Compiled blocks invalidated before the code memory is reclaimed.
+const MAX_STALE: usize = 4096;
+
+const UNSUITABLE: u32 = HOT;
+const COMPILED: u32 = HOT + 1;
+
+/// Runs a block over the register file and the arrays, returning how many
+/// instructions it completed. Fewer than the block length means it stopped
+/// at an instruction left to the interpreter: one that faults, or an
+/// Amendment of array 0.
+type BlockFn = unsafe extern "C" fn(*mut u32, *mut Vec<Option<Array>>) -> u32;
+
+/// Array Index for compiled code: the platter with bit 32 set, or zero if
+/// the access faults.
+unsafe extern "C" fn index(arrays: *const Vec<Option<Array>>, id: u32, offset: u32) -> u64 {
+    // SAFETY: `Jit::run` passes the machine's arrays, which nothing else
+    // borrows while a block runs.
+    match unsafe { &*arrays }.get(id as usize) {
+        Some(Some(array)) => array.platters().get(offset as usize).map_or(0, |&p| 1 << 32 | p as u64),
+        _ => 0,
+    }
+}
+
+/// Array Amendment for compiled code: nonzero if it was done, zero if it
+/// faults or targets array 0.
+unsafe extern "C" fn amend(arrays: *mut Vec<Option<Array>>, id: u32, offset: u32, value: u32) -> u32 {
+    if id == 0 {
+        return 0;
+    }
+    // SAFETY: as for `index`.
+    match unsafe { &mut *arrays }.get_mut(id as usize) {
+        Some(Some(array)) => array.amend(offset as usize, value).is_some() as u32,
+        _ => 0,
+    }
+}
+
+/// Whether a block can contain the operator.
+fn compilable(op: &Op) -> bool {
+    matches!(op.code, 0..=6 | 13)
+}
+
+struct Block {
+    run: BlockFn,
+    len: usize,
+}
+
+pub(crate) struct Jit {
+    module: JITModule,
+    /// Per address of array 0: an entry count below `HOT`, `UNSUITABLE`, or
+    /// `COMPILED + i` for `blocks[i]`.
+    slots: Vec<u32>,
+    blocks: Vec<Block>,
+    stale: usize,
+}
+
+impl fmt::Debug for Jit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Jit")
+            .field("blocks", &self.blocks.len())
+            .field("stale", &self.stale)
+            .finish()
+    }
+}
+
+fn new_module() -> Result<JITModule, String> {
+    let mut flags = settings::builder();
+    flags.set("opt_level", "speed").map_err(|e| e.to_string())?;
+    let isa = cranelift_native::builder()?
+        .finish(settings::Flags::new(flags))
+        .map_err(|e| e.to_string())?;
+    Ok(JITModule::new(JITBuilder::with_isa(isa, default_libcall_names())))
+}
+
+impl Jit {
+    pub fn new(len: usize) -> Result<Jit, String> {
+        Ok(Jit {
+            module: new_module()?,
+            slots: vec![0; len],
+            blocks: vec![],
+            stale: 0,
+        })
+    }
+
+    /// Forgets every block after array 0 has been replaced.
+    pub fn reset(&mut self, len: usize) {
+        if !self.blocks.is_empty() {
+            if let Ok(module) = new_module() {
+                let old = std::mem::replace(&mut self.module, module);
+                // SAFETY: the only pointers into the old module are in
+                // `blocks`, which is cleared below.
+                unsafe { old.free_memory() };
+            }
+            self.blocks.clear();
+        }
+        self.slots = vec![0; len];
+        self.stale = 0;
+    }
+
+    /// Drops any block that overlaps `offset` after it was amended.
+    pub fn invalidate(&mut self, offset: usize) {
+        let first = (offset + 1).saturating_sub(MAX_BLOCK);
+        for start in first..=offset.min(self.slots.len().saturating_sub(1)) {
+            let slot = self.slots[start];
+            if slot >= COMPILED {
+                if start + self.blocks[(slot - COMPILED) as usize].len <= offset {
+                    continue;
+                }
+                self.stale += 1;
+            }
+            self.slots[start] = 0;
+        }
+        if self.stale > MAX_STALE {
+            self.reset(self.slots.len());
+        }
+    }
+
+    /// Runs the block at `finger` if it is compiled and fits in `budget`,
+    /// returning the number of instructions executed.
+    #[inline]
+    pub fn run(
+        &mut self,
+        finger: usize,
+        code: &[Op],
+        registers: &mut [u32; 8],
+        arrays: &mut Vec<Option<Array>>,
+        budget: u64,
+    ) -> Option<usize> {
+        let slot = self.slots.get_mut(finger)?;
+        match *slot {
+            s if s >= COMPILED => {
+                let block = &self.blocks[(s - COMPILED) as usize];
+                if block.len as u64 > budget {
+                    return None;
+                }
+                // SAFETY: the block was compiled for the current contents of
+                // array 0 and only accesses the eight registers and, through
+                // `index` and `amend`, the arrays.
+                Some(unsafe { (block.run)(registers.as_mut_ptr(), arrays) } as usize)
+            }
+            UNSUITABLE => None,
+            s if s + 1 < HOT => {
+                *slot += 1;
+                None
+            }
+            _ => {
+                self.compile_at(finger, code);
+                None
+            }
+        }
+    }
+
+    fn compile_at(&mut self, finger: usize, code: &[Op]) {
+        let len = code[finger..]
+            .iter()
+            .take(MAX_BLOCK)
+            .take_while(|op| compilable(op))
+            .count();
+        self.slots[finger] = UNSUITABLE;
+        if len < MIN_BLOCK {
+            return;
+        }
+        if let Ok(run) = self.compile(&code[finger..finger + len]) {
+            self.slots[finger] = COMPILED + self.blocks.len() as u32;
+            self.blocks.push(Block { run, len });
+        }
+    }
+
+    fn compile(&mut self, ops: &[Op]) -> Result<BlockFn, String> {
+        let pointer = self.module.target_config().pointer_type();
+        let mut ctx = self.module.make_context();
+        ctx.func.signature.params.push(AbiParam::new(pointer));
+        ctx.func.signature.params.push(AbiParam::new(pointer));
+        ctx.func.signature.returns.push(AbiParam::new(types::I32));
+
+        let helper = |params: &[types::Type], ret| {
+            let mut sig: Signature = self.module.make_signature();
+            sig.params.extend(params.iter().map(|&t| AbiParam::new(t)));
+            sig.returns.push(AbiParam::new(ret));
+            sig
+        };
+        let index_sig = helper(&[pointer, types::I32, types::I32], types::I64);
+        let amend_sig = helper(&[pointer, types::I32, types::I32, types::I32], types::I32);
+
+        let mut fbc = FunctionBuilderContext::new();
+        let mut b = FunctionBuilder::new(&mut ctx.func, &mut fbc);
+        let entry = b.create_block();
+        b.append_block_params_for_function_params(entry);
+        b.switch_to_block(entry);
+        b.seal_block(entry);
+
+        let file = b.block_params(entry)[0];
+        let arrays = b.block_params(entry)[1];
+        let index_sig = b.import_signature(index_sig);
+        let amend_sig = b.import_signature(amend_sig);
+        let flags = MemFlags::trusted();
+        let mut regs = [Variable::from_u32(0); 8];
+        for (i, reg) in regs.iter_mut().enumerate() {
+            *reg = Variable::from_u32(i as u32);
+            b.declare_var(*reg, types::I32);
+            let value = b.ins().load(types::I32, flags, file, (i * 4) as i32);
+            b.def_var(*reg, value);
+        }
+
+        let store = |b: &mut FunctionBuilder, done: usize| {
+            for (i, reg) in regs.iter().enumerate() {
+                let value = b.use_var(*reg);
+                b.ins().store(flags, value, file, (i * 4) as i32);
+            }
+            let done = b.ins().iconst(types::I32, done as i64);
+            b.ins().return_(&[done]);
+        };
+        // Leaves the block before instruction `done` unless `ok` is nonzero.
+        let exit_unless = |b: &mut FunctionBuilder, ok: Value, done: usize| {
+            let exit = b.create_block();
+            let next = b.create_block();
+            b.ins().brif(ok, next, &[], exit, &[]);
+            b.switch_to_block(exit);
+            b.seal_block(exit);
+            store(b, done);
+            b.switch_to_block(next);
+            b.seal_block(next);
+        };
+
+        for (i, op) in ops.iter().enumerate() {
+            let a = regs[op.a as usize];
+            let rb: Value = b.use_var(regs[op.b as usize]);
+            let rc: Value = b.use_var(regs[op.c as usize]);
+            let value = match op.code {
+                // Conditional Move
+                0 => {
+                    let ra = b.use_var(a);
+                    b.ins().select(rc, rb, ra)
+                }
+                // Array Index
+                1 => {
+                    let callee = b.ins().iconst(pointer, index as *const () as i64);
+                    let call = b.ins().call_indirect(index_sig, callee, &[arrays, rb, rc]);
+                    let result = b.inst_results(call)[0];
+                    let ok = b.ins().ushr_imm(result, 32);
+                    exit_unless(&mut b, ok, i);
+                    b.ins().ireduce(types::I32, result)
+                }
+                // Array Amendment
+                2 => {
+                    let ra = b.use_var(a);
+                    let callee = b.ins().iconst(pointer, amend as *const () as i64);
+                    let call = b.ins().call_indirect(amend_sig, callee, &[arrays, ra, rb, rc]);
+                    let done = b.inst_results(call)[0];
+                    exit_unless(&mut b, done, i);
+                    continue;
+                }
+                // Addition
+                3 => b.ins().iadd(rb, rc),
+                // Multiplication
+                4 => b.ins().imul(rb, rc),
+                // Division
+                5 => {
+                    exit_unless(&mut b, rc, i);
+                    b.ins().udiv(rb, rc)
+                }
+                // Nand
+                6 => {
+                    let and = b.ins().band(rb, rc);
+                    b.ins().bnot(and)
+                }
+                // Orthography
+                13 => b.ins().iconst(types::I32, op.value as i64),
+                _ => unreachable!("not a compilable operator"),
+            };
+            b.def_var(a, value);
+        }
+        store(&mut b, ops.len());
+        b.finalize();
+
+        let id = self
+            .module
+            .declare_anonymous_function(&ctx.func.signature)
+            .map_err(|e| e.to_string())?;
+        self.module.define_function(id, &mut ctx).map_err(|e| e.to_string())?;
+        self.module.clear_context(&mut ctx);
+        self.module.finalize_definitions().map_err(|e| e.to_string())?;
+
+        // SAFETY: the function was declared with the `BlockFn` signature.
+        Ok(unsafe { std::mem::transmute::<*const u8, BlockFn>(self.module.get_finalized_function(id)) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::code::decode_all;
+    use crate::platter::{make_orthography, make_platter};
+    use crate::{Outcome, UM};
+
+    /// Counts r1 down from `n`, dividing by it each time round the loop.
+    fn countdown(n: u32) -> Vec<u32> {
+        vec![
+            make_orthography(0, 0),
+            make_orthography(1, n),
+            make_orthography(4, 0),
+            make_platter(6, 4, 4, 4),
+            make_orthography(6, 11),
+            make_orthography(7, 5),
+            // 5: loop
+            make_platter(3, 1, 1, 4),
+            make_platter(5, 3, 6, 1),
+            make_platter(0, 5, 6, 6),
+            make_platter(0, 5, 7, 1),
+            make_platter(12, 0, 0, 5),
+            // 11: exit
+            make_platter(7, 0, 0, 0),
+        ]
+    }
+
+    /// Sums into an allocated array, copying each sum into array 0 too.
+    fn prefix_sums() -> Vec<u32> {
+        vec![
+            make_orthography(1, 64),
+            make_platter(8, 0, 2, 1),
+            make_orthography(1, 19),
+            make_orthography(3, 7),
+            make_orthography(4, 0),
+            make_orthography(6, 62),
+            make_platter(6, 6, 6, 6),      // r6 = -63
+            // 7: loop
+            make_platter(1, 5, 2, 4),
+            make_platter(3, 5, 5, 4),
+            make_orthography(7, 1),
+            make_platter(3, 4, 4, 7),
+            make_platter(2, 2, 4, 5),
+            make_platter(2, 0, 1, 5),      // ends the compiled block
+            make_platter(3, 7, 4, 6),
+            make_orthography(5, 17),
+            make_platter(0, 5, 3, 7),
+            make_platter(12, 0, 0, 5),
+            // 17: exit
+            make_platter(7, 0, 0, 0),
+            0,
+            0,
+        ]
+    }
+
+    fn assert_same(program: Vec<u32>, budget: u64) -> Outcome {
+        let mut interpreted = UM::new(program.clone());
+        let mut compiled = UM::new(program);
+        compiled.enable_jit().unwrap();
+        loop {
+            let expected = interpreted.run_until(budget);
+            assert_eq!(expected, compiled.run_until(budget));
+            assert_eq!(interpreted.registers(), compiled.registers());
+            assert_eq!(interpreted.finger(), compiled.finger());
+            assert_eq!(interpreted.cycles(), compiled.cycles());
+            for id in 0..2 {
+                assert_eq!(interpreted.array(id), compiled.array(id));
+            }
+            if expected != Outcome::BudgetExhausted {
+                return expected;
+            }
+        }
+    }
+
+    #[test]
+    fn test_matches_interpreter() {
+        for budget in [1, 3, 7, 1000] {
+            match assert_same(countdown(100), budget) {
+                Outcome::Fault(fault) => assert_eq!(crate::FaultKind::DivisionByZero, fault.kind),
+                outcome => panic!("unexpected {:?}", outcome),
+            }
+        }
+    }
+
+    #[test]
+    fn test_array_access_matches_interpreter() {
+        for budget in [1, 4, 1000] {
+            assert_eq!(Outcome::Halted, assert_same(prefix_sums(), budget));
+        }
+    }
+
+    #[test]
+    fn test_blocks_span_array_access() {
+        let program = prefix_sums();
+        let code = decode_all(&program);
+        let mut arrays = vec![Some(Array::new(program)), Some(Array::new(vec![5; 64]))];
+        let mut registers = [0, 19, 1, 7, 0, 0, (-63_i32) as u32, 0];
+        let mut jit = Jit::new(code.len()).unwrap();
+        for _ in 0..HOT {
+            assert_eq!(None, jit.run(7, &code, &mut registers, &mut arrays, 100));
+        }
+        // Index, the arithmetic and the amendment of array 1, stopping at
+        // the amendment of array 0.
+        assert_eq!(Some(5), jit.run(7, &code, &mut registers, &mut arrays, 100));
+        assert_eq!([0, 19, 1, 7, 1, 5, (-63_i32) as u32, 1], registers);
+        assert_eq!(5, arrays[1].as_ref().unwrap().platters()[1]);
+
+        registers[4] = 70;
+        assert_eq!(Some(0), jit.run(7, &code, &mut registers, &mut arrays, 100));
+    }
+
+    #[test]
+    fn test_self_modification_invalidates() {
+        let mut program = countdown(100);
+        // Overwrite the division with an addition once the loop is hot.
+        program[7] = make_platter(3, 3, 6, 1);
+        let mut um = UM::new(program);
+        um.enable_jit().unwrap();
+        assert_eq!(Outcome::BudgetExhausted, um.run_until(300));
+        assert!(um.amend(0, 7, make_platter(5, 3, 6, 1)));
+        match um.run_until(u64::MAX) {
+            Outcome::Fault(fault) => assert_eq!(7, fault.finger),
+            outcome => panic!("unexpected {:?}", outcome),
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! A Universal Machine for the ICFP Programming Contest 2006.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use bytes::{Buf, BufMut, Bytes};
+
+mod array;
+pub mod asm;
+mod code;
+pub mod codex;
+pub mod console;
+pub mod debug;
+pub mod disasm;
+pub mod expect;
+mod fault;
+pub mod gdb;
+pub mod instruction;
+#[cfg(feature = "jit")]
+mod jit;
+pub mod platter;
+pub mod profile;
+pub mod replay;
+pub mod snapshot;
+pub mod trace;
+pub mod translate;
+mod um;
+
+pub use console::{Console, IoConsole, OutputMode, StdConsole};
+pub use fault::{Error, Fault, FaultKind};
+pub use instruction::{DecodeError, Instruction};
+pub use snapshot::Snapshot;
+pub use um::{Outcome, FLUSH_INTERVAL, UM};
+
+/// Reads a big-endian UM image into platters.
+pub fn read_file_to_vec(path: &str) -> std::io::Result<Vec<u32>> {
+    let mut file = File::open(path)?;
+    let mut buf  = vec![];
+    let mut vec: Vec<u32>  = vec![];
+
+    file.read_to_end(&mut buf)?;
+
+    let mut buf = Bytes::from(buf);
+
+    while buf.has_remaining() {
+        vec.push(buf.get_u32());
+    }
+
+    Ok(vec)
+}
+
+/// Writes platters as a big-endian UM image.
+pub fn write_vec_to_file(path: &str, platters: &[u32]) -> std::io::Result<()> {
+    let mut buf = Vec::with_capacity(platters.len() * 4);
+
+    for &p in platters {
+        buf.put_u32(p);
+    }
+
+    File::create(path)?.write_all(&buf)
+}
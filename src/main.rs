@@ -1,205 +1,411 @@
-use std::{fs::File, io::Write};
-use std::io;
-use std::io::Read;
-use bytes::{Buf, Bytes};
 use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Cursor, Write};
+use std::net::TcpListener;
+use std::process;
 
-fn main() {
-    let mut um = UM::default();
-    let args: Vec<String> = env::args().collect();
-    let file = args.get(1).expect("You must specify the UM binary file.");
-    let buf = read_file_to_vec(file).unwrap();
+use icfp2006_rust::instruction::MNEMONICS;
+use icfp2006_rust::{
+    asm, codex, console, debug, disasm, expect, gdb, profile, read_file_to_vec, replay, snapshot, trace, translate,
+    write_vec_to_file, Error, IoConsole, OutputMode, Snapshot, FLUSH_INTERVAL, UM,
+};
 
-    um.programs.push(buf);
+/// Number of addresses listed in a `--profile` report.
+const PROFILE_TOP: usize = 50;
 
-    um.spin_cycle();
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("asm") => assemble(&args[1..]),
+        Some("debug") => debug_image(&args[1..]),
+        Some("disasm") => disassemble(&args[1..]),
+        Some("extract-codex") => extract_codex(&args[1..]),
+        Some("gdb") => serve_gdb(&args[1..]),
+        Some("trace") => print_trace(&args[1..]),
+        Some("translate") => translate_image(&args[1..]),
+        _ => run(&args),
+    }
 }
 
-fn read_file_to_vec(path: &str) -> std::io::Result<Vec<u32>> {
-    let mut file = File::open(path)?;
-    let mut buf  = vec![];
-    let mut vec: Vec<u32>  = vec![];
+fn run(args: &[String]) {
+    let mut args = args.iter().cloned();
+    let mut file = None;
+    let mut mode = OutputMode::Raw;
+    let mut flush_interval = FLUSH_INTERVAL;
+    let mut jit = false;
+    let mut trace_file = None;
+    let mut profile_file = None;
+    let mut flamegraph_file = None;
+    let mut restore_file = None;
+    let mut halt_snapshot = None;
+    let mut hotkey_snapshot = None;
+    let mut record_file = None;
+    let mut replay_file = None;
+    let mut input_files = vec![];
+    let mut expect_file = None;
+    let mut normalize_newlines = false;
 
-    file.read_to_end(&mut buf)?;
-
-    let mut buf = Bytes::from(buf);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output-mode" => {
+                let value = args.next().expect("--output-mode needs raw, latin1 or escaped");
+                mode = value.parse().unwrap_or_else(|e: String| usage(&e));
+            }
+            "--flush-interval" => {
+                let value = args.next().expect("--flush-interval needs a cycle count");
+                flush_interval = value.parse().unwrap_or_else(|_| usage("invalid --flush-interval"));
+            }
+            "--jit" => jit = true,
+            "--trace" => trace_file = Some(args.next().expect("--trace needs a file name")),
+            "--profile" => profile_file = Some(args.next().expect("--profile needs a file name")),
+            "--flamegraph" => {
+                flamegraph_file = Some(args.next().expect("--flamegraph needs a file name"));
+            }
+            "--restore" => restore_file = Some(args.next().expect("--restore needs a file name")),
+            "--snapshot-on-halt" => {
+                halt_snapshot = Some(args.next().expect("--snapshot-on-halt needs a file name"));
+            }
+            "--snapshot-hotkey" => {
+                hotkey_snapshot = Some(args.next().expect("--snapshot-hotkey needs a file name"));
+            }
+            "--record" => record_file = Some(args.next().expect("--record needs a file name")),
+            "--replay" => replay_file = Some(args.next().expect("--replay needs a file name")),
+            "--input" => input_files.push(args.next().expect("--input needs a file name")),
+            "--normalize-newlines" => normalize_newlines = true,
+            "--expect" => expect_file = Some(args.next().expect("--expect needs a file name")),
+            _ => file = Some(arg),
+        }
+    }
 
-    while buf.has_remaining() {
-        vec.push(buf.get_u32());
+    // Scripted input comes first, then the terminal.
+    let mut script = vec![];
+    for path in &input_files {
+        let text = fs::read(path).unwrap_or_else(|e| usage(&format!("{}: {}", path, e)));
+        script.extend(console::script_input(&text, normalize_newlines));
+    }
+    let console = IoConsole::new(Cursor::new(script), BufWriter::new(io::stdout()))
+        .with_fallback(io::stdin())
+        .with_output_mode(mode);
+    let mut um = match restore_file {
+        Some(path) => {
+            let file = File::open(&path).unwrap_or_else(|e| usage(&format!("{}: {}", path, e)));
+            let mut input = BufReader::new(file);
+            let snapshot = Snapshot::read_from(&mut input).unwrap_or_else(|e| usage(&e.to_string()));
+            UM::restore(snapshot, console)
+        }
+        None => {
+            let file = file.expect("You must specify the UM binary file.");
+            UM::with_console(read_file_to_vec(&file).unwrap(), console)
+        }
+    };
+    um.set_flush_interval(flush_interval);
+    let profiling = profile_file.is_some() || flamegraph_file.is_some();
+    let drivers = [
+        trace_file.is_some(),
+        profiling,
+        hotkey_snapshot.is_some(),
+        record_file.is_some(),
+        replay_file.is_some(),
+        expect_file.is_some(),
+    ];
+    if drivers.iter().filter(|&&d| d).count() > 1 {
+        usage("--trace, --profile, --snapshot-hotkey, --record, --replay and --expect cannot be combined");
+    }
+    if jit {
+        if trace_file.is_some() || profiling {
+            usage("--trace, --profile and --flamegraph cannot be combined with --jit");
+        }
+        enable_jit(&mut um);
     }
 
-    Ok(vec)
-}
+    let result = if let Some(path) = trace_file {
+        let out = File::create(&path).unwrap_or_else(|e| usage(&format!("{}: {}", path, e)));
+        let mut writer = trace::TraceWriter::new(BufWriter::new(out)).unwrap();
+        trace::run_traced(&mut um, &mut writer)
+    } else if profiling {
+        let mut profile = match flamegraph_file {
+            Some(_) => profile::Profile::with_call_stacks(),
+            None => profile::Profile::new(),
+        };
+        let result = profile::run_profiled(&mut um, &mut profile);
+        if let Some(path) = profile_file {
+            let out = File::create(&path).unwrap_or_else(|e| usage(&format!("{}: {}", path, e)));
+            let mut out = BufWriter::new(out);
+            profile.report(PROFILE_TOP, &mut out).and_then(|_| out.flush()).unwrap();
+        }
+        if let (Some(path), Some(stacks)) = (flamegraph_file, &profile.stacks) {
+            let out = File::create(&path).unwrap_or_else(|e| usage(&format!("{}: {}", path, e)));
+            let mut out = BufWriter::new(out);
+            stacks.write_folded(&mut out).and_then(|_| out.flush()).unwrap();
+        }
+        result
+    } else if let Some(path) = hotkey_snapshot {
+        snapshot::run_with_hotkey(&mut um, |um| {
+            save_snapshot(um, &path)?;
+            eprintln!("\nUM: snapshot saved to {}", path);
+            Ok(())
+        })
+    } else if let Some(path) = record_file {
+        let out = File::create(&path).unwrap_or_else(|e| usage(&format!("{}: {}", path, e)));
+        let mut writer = replay::RecordingWriter::new(BufWriter::new(out)).unwrap();
+        replay::run_recorded(&mut um, &mut writer)
+    } else if let Some(path) = replay_file {
+        let input = File::open(&path).unwrap_or_else(|e| usage(&format!("{}: {}", path, e)));
+        let input = BufReader::new(input);
+        let reader = replay::RecordingReader::new(input).unwrap_or_else(|e| usage(&e.to_string()));
+        match replay::replay(&mut um, reader) {
+            Ok(Some(divergence)) => {
+                eprintln!("\nUM: {}", divergence);
+                process::exit(3);
+            }
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        }
+    } else if let Some(path) = expect_file {
+        let text = fs::read_to_string(&path).unwrap_or_else(|e| usage(&format!("{}: {}", path, e)));
+        let script = expect::Script::parse(&text)
+            .unwrap_or_else(|e| usage(&format!("{}:{}: {}", path, e.line, e.message)));
+        match expect::run_expect(&mut um, &script) {
+            Ok(Some(unmet)) => {
+                eprintln!("\nUM: {}", unmet);
+                process::exit(3);
+            }
+            Ok(None) => Ok(()),
+            Err(e) => Err(e),
+        }
+    } else {
+        um.spin_cycle()
+    };
 
-#[inline]
-fn op_code(p: u32) -> u8 {
-    (p >> 28) as u8
+    match result {
+        Ok(()) => {
+            if let Some(path) = halt_snapshot {
+                save_snapshot(&um, &path).unwrap();
+            }
+            println!("\nUM: Halt.");
+            println!("{:?}", um.registers());
+        }
+        Err(e @ Error::Fault(_)) => {
+            eprintln!("\nUM: {}", e);
+            process::exit(2);
+        }
+        Err(e) => {
+            eprintln!("\nUM: {}", e);
+            process::exit(1);
+        }
+    }
 }
 
-#[inline]
-fn rega_offset(p: u32) -> usize {
-    ((p >> 6) & 7) as usize
+fn save_snapshot<C>(um: &UM<C>, path: &str) -> io::Result<()>
+where
+    C: icfp2006_rust::Console,
+{
+    let mut out = BufWriter::new(File::create(path)?);
+    um.snapshot().write_to(&mut out)?;
+    out.flush()
 }
 
-#[inline]
-fn regb_offset(p: u32) -> usize {
-    ((p >> 3) & 7) as usize
-}
+fn assemble(args: &[String]) {
+    let mut args = args.iter().cloned();
+    let mut file = None;
+    let mut output = None;
 
-#[inline]
-fn regc_offset(p: u32) -> usize {
-    (p & 7) as usize
-}
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(args.next().expect("-o needs a file name")),
+            _ => file = Some(arg),
+        }
+    }
 
-#[inline]
-fn rego_offset(p: u32) -> usize {
-    ((p >> 25) & 7) as usize
-}
+    let file = file.expect("You must specify the assembly source file.");
+    let output = output.unwrap_or_else(|| format!("{}.um", file.trim_end_matches(".s")));
+    let source = fs::read_to_string(&file).unwrap();
 
-#[inline]
-fn rego_value(p: u32) -> u32  {
-    p & 0x1ffffff
+    match asm::assemble(&source) {
+        Ok(image) => write_vec_to_file(&output, &image).unwrap(),
+        Err(e) => usage(&format!("{}:{}: {}", file, e.line, e.message)),
+    }
 }
 
-#[derive(Debug, Default)]
-struct UM {
-    registers: [u32; 8],
-    programs: Vec<Vec<u32>>,
-    finger: usize,
-    freelist: Vec<u32>,
+fn debug_image(args: &[String]) {
+    let mut args = args.iter().cloned();
+    let mut file = None;
+    let mut mode = OutputMode::Raw;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output-mode" => {
+                let value = args.next().expect("--output-mode needs raw, latin1 or escaped");
+                mode = value.parse().unwrap_or_else(|e: String| usage(&e));
+            }
+            _ => file = Some(arg),
+        }
+    }
+
+    let file = file.expect("You must specify the UM binary file.");
+    let buf = read_file_to_vec(&file).unwrap();
+
+    let console = IoConsole::default().with_output_mode(mode);
+    let mut um = UM::with_console(buf, console);
+    let mut debugger = debug::Debugger::new(&mut um, io::stdout());
+    debugger.run(|line| io::stdin().read_line(line)).unwrap();
 }
 
-impl UM {
-    fn spin_cycle(&mut self) {
-        let mut p: u32;
-        let mut a: usize;
-        let mut b: usize;
-        let mut c: usize;
+fn serve_gdb(args: &[String]) {
+    let mut args = args.iter().cloned();
+    let mut file = None;
+    let mut port = 1234;
 
-        macro_rules! reg {
-            ($x:ident) => {
-                self.registers[$x]
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => {
+                let value = args.next().expect("--port needs a port number");
+                port = value.parse().unwrap_or_else(|_| usage("invalid --port"));
             }
+            _ => file = Some(arg),
         }
+    }
 
-        loop {
-            p = self.programs[0][self.finger];
-            a = rega_offset(p);
-            b = regb_offset(p);
-            c = regc_offset(p);
-
-            // println!("Register: {:?}", self.registers);
-            // println!("Finger: {}, OP: {}", self.finger, op_code(p));
-
-            match op_code(p) {
-                // Conditional Move
-                0 => if reg!(c) != 0 { reg!(a) = reg!(b) },
-
-                // Array Index
-                1 => reg!(a) = self.programs[reg!(b) as usize][reg!(c) as usize],
-
-                // Array Amendment
-                2 => self.programs[reg!(a) as usize][reg!(b) as usize] = reg!(c),
-
-                // Addition
-                3 => reg!(a) = reg!(b).wrapping_add(reg!(c)),
-
-                // Multiplication
-                4 => reg!(a) = reg!(b).wrapping_mul(reg!(c)),
-                // Division
-                5 => reg!(a) = reg!(b).wrapping_div(reg!(c)),
-                // Nand
-                6 => reg!(a) = !(reg!(b) & reg!(c)),
-                // Halt
-                7 => {
-                    println!("\nUM: Halt.");
-                    println!("{:?}", self.registers);
-                    break;
-                },
-                // Allocation
-                8 => {
-                    let array = vec![0; reg!(c) as usize];
-                    if let Some(i) = self.freelist.pop() {
-                        reg!(b) = i;
-                        self.programs[i as usize] = array;
-                    } else {
-                        reg!(b) = self.programs.len() as u32;
-                        self.programs.push(array);
-                    }
-                },
-                // Abandonment
-                9 => {
-                    self.freelist.push(reg!(c));
-                    self.programs[reg!(c) as usize].clear();
-                    self.programs[reg!(c) as usize].shrink_to_fit();
-                },
-                // Output
-                10 => {
-                    print!("{}", char::from_u32(reg!(c)).unwrap());
-                    io::stdout().flush().unwrap();
-                },
-                // Input
-                11 => {
-                    let mut buf = [0_u8];
-                    io::stdin().read_exact(&mut buf).unwrap();
-                    reg!(c) = buf[0] as u32;
-                }
-                // Load Program
-                12 => {
-                    if reg!(b) != 0 {
-                        let array = self.programs[reg!(b) as usize].clone();
-                        self.programs[0] = array;
-                    }
-                    self.finger = reg!(c) as usize;
-                    continue;
-                },
-                // Orthography
-                13 => self.registers[rego_offset(p)] = rego_value(p),
-                _ => {
-                    println!("Unknown OP: {}", op_code(p));
-                },
-            }
-            self.finger += 1;
+    let file = file.expect("You must specify the UM binary file.");
+    let buf = read_file_to_vec(&file).unwrap();
+    let mut um = UM::with_console(buf, IoConsole::default());
+
+    let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+    eprintln!("UM: waiting for GDB on {}", listener.local_addr().unwrap());
+    let (stream, _) = listener.accept().unwrap();
+    let end = gdb::GdbStub::new(&mut um, stream).and_then(|mut stub| stub.serve()).unwrap();
+
+    if end == gdb::End::Detached {
+        if let Err(e) = um.spin_cycle() {
+            eprintln!("\nUM: {}", e);
+            process::exit(1);
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::*;
+fn disassemble(args: &[String]) {
+    let file = args.first().expect("You must specify the UM binary file.");
+    let image = read_file_to_vec(file).unwrap();
 
-    fn make_platter(op: u8, a: u8, b: u8, c: u8) -> u32 {
-        assert!(op <= 13);
-        assert!(a <= 7);
-        assert!(b <= 7);
-        assert!(c <= 7);
+    let mut out = BufWriter::new(io::stdout());
+    disasm::disassemble(&image, &mut out).unwrap();
+    out.flush().unwrap();
+}
 
-        let op = (op as u32) << 28;
-        let a  = (a  as u32) << 6;
-        let b  = (b  as u32) << 3;
-        let c  = c as u32;
+fn extract_codex(args: &[String]) {
+    let mut args = args.iter().cloned();
+    let mut file = None;
+    let mut key = codex::KEY.to_string();
+    let mut output = "umix.um".to_string();
 
-        op | a | b | c
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--key" => key = args.next().expect("--key needs a decryption key"),
+            "-o" => output = args.next().expect("-o needs a file name"),
+            _ => file = Some(arg),
+        }
+    }
+
+    let file = file.expect("You must specify the codex file.");
+    let mut um = UM::new(read_file_to_vec(&file).unwrap());
+    let image = codex::extract(&mut um, &key).unwrap_or_else(|e| usage(&format!("\nUM: {}", e)));
+    fs::write(&output, &image).unwrap();
+    eprintln!("\nUM: wrote {} platters to {}", image.len() / 4, output);
 }
 
-    #[test]
-    fn test_op_code() {
-        let p = make_platter(0b0101, 0, 1, 2);
-        assert_eq!(0b0101, op_code(p));
+fn print_trace(args: &[String]) {
+    let mut args = args.iter().cloned();
+    let mut file = None;
+    let mut filter = trace::Filter::default();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cycles" => {
+                let value = args.next().expect("--cycles needs a range");
+                let (start, end) = parse_range(&value).unwrap_or_else(|| usage("invalid --cycles"));
+                filter.cycles = Some(start..=end);
+            }
+            "--finger" => {
+                let value = args.next().expect("--finger needs an address or range");
+                let (start, end) = parse_range(&value).unwrap_or_else(|| usage("invalid --finger"));
+                let clamp = |n: u64| n.min(u32::MAX as u64) as u32;
+                filter.fingers = Some(clamp(start)..=clamp(end));
+            }
+            "--op" => {
+                let value = args.next().expect("--op needs a mnemonic or operator number");
+                let op = MNEMONICS
+                    .iter()
+                    .position(|m| *m == value)
+                    .or_else(|| value.parse().ok().filter(|&op| op < 16))
+                    .unwrap_or_else(|| usage(&format!("unknown operator {}", value)));
+                filter.op_codes.push(op as u8);
+            }
+            _ => file = Some(arg),
+        }
     }
 
-    #[test]
-    fn test_reg_offsets() {
-        let p = make_platter(0, 1, 2, 3);
-        assert_eq!(1, rega_offset(p));
-        assert_eq!(2, regb_offset(p));
-        assert_eq!(3, regc_offset(p));
+    let file = file.expect("You must specify the trace file.");
+    let input = BufReader::new(File::open(file).unwrap());
+    let reader = trace::TraceReader::new(input).unwrap_or_else(|e| usage(&e.to_string()));
+
+    let mut out = BufWriter::new(io::stdout());
+    trace::print(reader, &filter, &mut out).unwrap_or_else(|e| usage(&e.to_string()));
+    out.flush().unwrap();
+}
+
+/// Parses `N`, `A..B`, `A..` or `..B`, with inclusive ends, in decimal or hex.
+fn parse_range(s: &str) -> Option<(u64, u64)> {
+    let number = |s: &str| match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    };
+    match s.split_once("..") {
+        None => number(s).map(|n| (n, n)),
+        Some((start, end)) => {
+            let start = if start.is_empty() { 0 } else { number(start)? };
+            let end = if end.is_empty() { u64::MAX } else { number(end)? };
+            Some((start, end))
+        }
     }
+}
 
-    #[test]
-    fn test_13_orthography() {
-        let p: u32 = 0b00001110000000000000000000000001;
-        assert_eq!(7, rego_offset(p));
-        assert_eq!(1, rego_value(p));
+fn translate_image(args: &[String]) {
+    let mut args = args.iter().cloned();
+    let mut file = None;
+    let mut output = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => output = Some(args.next().expect("-o needs a file name")),
+            _ => file = Some(arg),
+        }
     }
+
+    let file = file.expect("You must specify the UM binary file.");
+    let image = read_file_to_vec(&file).unwrap();
+
+    let out: Box<dyn Write> = match output {
+        Some(path) => Box::new(File::create(path).unwrap()),
+        None => Box::new(io::stdout()),
+    };
+    let mut out = BufWriter::new(out);
+    translate::translate(&image, &mut out).unwrap();
+    out.flush().unwrap();
+}
+
+#[cfg(feature = "jit")]
+fn enable_jit<C: icfp2006_rust::Console>(um: &mut UM<C>) {
+    um.enable_jit().unwrap_or_else(|e| usage(&e));
+}
+
+#[cfg(not(feature = "jit"))]
+fn enable_jit<C>(_: &mut UM<C>) {
+    usage("--jit requires building with the jit feature");
+}
+
+fn usage(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
 }
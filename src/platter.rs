@@ -0,0 +1,80 @@
+//! Field extraction for the 32-bit instruction platters.
+
+#[inline]
+pub fn op_code(p: u32) -> u8 {
+    (p >> 28) as u8
+}
+
+#[inline]
+pub fn rega_offset(p: u32) -> usize {
+    ((p >> 6) & 7) as usize
+}
+
+#[inline]
+pub fn regb_offset(p: u32) -> usize {
+    ((p >> 3) & 7) as usize
+}
+
+#[inline]
+pub fn regc_offset(p: u32) -> usize {
+    (p & 7) as usize
+}
+
+#[inline]
+pub fn rego_offset(p: u32) -> usize {
+    ((p >> 25) & 7) as usize
+}
+
+#[inline]
+pub fn rego_value(p: u32) -> u32  {
+    p & 0x1ffffff
+}
+
+#[cfg(test)]
+pub(crate) fn make_platter(op: u8, a: u8, b: u8, c: u8) -> u32 {
+    assert!(op <= 13);
+    assert!(a <= 7);
+    assert!(b <= 7);
+    assert!(c <= 7);
+
+    let op = (op as u32) << 28;
+    let a  = (a  as u32) << 6;
+    let b  = (b  as u32) << 3;
+    let c  = c as u32;
+
+    op | a | b | c
+}
+
+#[cfg(test)]
+pub(crate) fn make_orthography(a: u8, value: u32) -> u32 {
+    assert!(a <= 7);
+    assert!(value <= 0x1ffffff);
+
+    (13 << 28) | ((a as u32) << 25) | value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_op_code() {
+        let p = make_platter(0b0101, 0, 1, 2);
+        assert_eq!(0b0101, op_code(p));
+    }
+
+    #[test]
+    fn test_reg_offsets() {
+        let p = make_platter(0, 1, 2, 3);
+        assert_eq!(1, rega_offset(p));
+        assert_eq!(2, regb_offset(p));
+        assert_eq!(3, regc_offset(p));
+    }
+
+    #[test]
+    fn test_13_orthography() {
+        let p: u32 = 0b00001110000000000000000000000001;
+        assert_eq!(7, rego_offset(p));
+        assert_eq!(1, rego_value(p));
+    }
+}
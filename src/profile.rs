@@ -0,0 +1,286 @@
+//! Execution profiles.
+//!
+//! `run_profiled` counts executions per operator and per finger address.
+//! Addresses are counted per loaded program: the image the machine started
+//! with is program 0 and every Load Program that replaces array 0 with
+//! different contents starts the next one, so the counts of different
+//! programs never mix. Loading the same contents again goes on counting
+//! into the earlier program.
+//!
+//! A profile can also count instructions per call stack, as recovered by
+//! `CallStacks`, for flame graphs.
+
+mod stacks;
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::io::Write;
+
+use crate::console::Console;
+use crate::disasm;
+use crate::fault::Error;
+use crate::instruction::MNEMONICS;
+use crate::platter::*;
+use crate::um::{Outcome, UM};
+
+pub use stacks::CallStacks;
+
+/// Execution counts for one program in array 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    /// The array the program was first loaded from, `None` for the initial
+    /// image.
+    pub source: Option<u32>,
+    /// Cycle at which it was first loaded.
+    pub loaded_at: u64,
+    /// How many times it was loaded.
+    pub loads: u64,
+    pub counts: Vec<u64>,
+    /// Array 0 as it was first loaded, for disassembly.
+    pub platters: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub op_counts: [u64; 16],
+    pub programs: Vec<Program>,
+    pub stacks: Option<CallStacks>,
+    /// Programs by a hash of their platters.
+    index: HashMap<u64, usize>,
+    /// The program in array 0.
+    current: usize,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Profile::default()
+    }
+
+    /// Creates a profile that also counts per recovered call stack.
+    pub fn with_call_stacks() -> Self {
+        Profile { stacks: Some(CallStacks::new()), ..Profile::default() }
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.op_counts.iter().sum()
+    }
+
+    /// Switches to the program with the given platters, which only get
+    /// copied the first time they are loaded.
+    fn begin(&mut self, source: Option<u32>, loaded_at: u64, platters: &[u32]) {
+        let mut hasher = DefaultHasher::new();
+        platters.hash(&mut hasher);
+        let hash = hasher.finish();
+        self.current = match self.index.get(&hash) {
+            Some(&i) if self.programs[i].platters == platters => i,
+            _ => {
+                let counts = vec![0; platters.len()];
+                let program = Program { source, loaded_at, loads: 0, counts, platters: platters.to_vec() };
+                self.programs.push(program);
+                self.index.entry(hash).or_insert(self.programs.len() - 1);
+                self.programs.len() - 1
+            }
+        };
+        self.programs[self.current].loads += 1;
+        if let Some(stacks) = &mut self.stacks {
+            stacks.load(self.current as u32);
+        }
+    }
+
+    /// Writes the operator histogram and the `top` hottest addresses.
+    pub fn report<W: Write>(&self, top: usize, out: &mut W) -> io::Result<()> {
+        let total = self.cycles().max(1) as f64;
+        let percent = |n: u64| 100.0 * n as f64 / total;
+
+        writeln!(out, "UM profile: {} instructions\n", self.cycles())?;
+        writeln!(out, "operator        count       %")?;
+        let mut ops: Vec<(usize, u64)> =
+            self.op_counts.iter().copied().enumerate().filter(|(_, n)| *n > 0).collect();
+        ops.sort_by_key(|&(op, n)| (std::cmp::Reverse(n), op));
+        for (op, n) in ops {
+            let name = MNEMONICS.get(op).copied().unwrap_or("?");
+            writeln!(out, "{:<8} {:>12} {:>6.2}%", name, n, percent(n))?;
+        }
+
+        writeln!(out, "\nprogram  source   loads  first loaded at cycle")?;
+        for (i, p) in self.programs.iter().enumerate() {
+            let source = p.source.map_or("image".to_string(), |a| format!("[{}]", a));
+            writeln!(out, "{:>7}  {:>6}  {:>6}  {}", i, source, p.loads, p.loaded_at)?;
+        }
+
+        writeln!(out, "\nprogram  address         count       %  instruction")?;
+        let mut hot: Vec<(usize, usize, u64)> = vec![];
+        for (i, p) in self.programs.iter().enumerate() {
+            let executed = p.counts.iter().enumerate().filter(|(_, n)| **n > 0);
+            hot.extend(executed.map(|(addr, n)| (i, addr, *n)));
+        }
+        hot.sort_by_key(|&(i, addr, n)| (std::cmp::Reverse(n), i, addr));
+        for (i, addr, n) in hot.into_iter().take(top) {
+            let platter = self.programs[i].platters.get(addr).copied().unwrap_or(0);
+            let text = disasm::render(platter);
+            writeln!(out, "{:>7}  {:08x} {:>12} {:>6.2}%  {}", i, addr, n, percent(n), text)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs `um` to a halt, counting every executed instruction into `profile`.
+pub fn run_profiled<C: Console>(um: &mut UM<C>, profile: &mut Profile) -> Result<(), Error> {
+    if profile.programs.is_empty() {
+        profile.begin(None, um.cycles(), um.array(0).unwrap_or(&[]));
+    }
+    let result = count(um, profile);
+    um.console_mut().flush()?;
+    result
+}
+
+fn count<C: Console>(um: &mut UM<C>, profile: &mut Profile) -> Result<(), Error> {
+    loop {
+        let finger = um.finger();
+        let Some(&platter) = um.array(0).and_then(|a| a.get(finger)) else {
+            // Let the machine report the fault.
+            if let Outcome::Fault(fault) = um.step() {
+                return Err(fault.into());
+            }
+            continue;
+        };
+        let op = op_code(platter);
+        let registers = um.registers();
+        let source = registers[regb_offset(platter)];
+        let target = registers[regc_offset(platter)];
+        let replaces_program = op == 12 && source != 0;
+        let linked = op == 12 && registers.contains(&(finger as u32).wrapping_add(1));
+
+        let outcome = um.step();
+        match outcome {
+            Outcome::Fault(fault) => return Err(fault.into()),
+            Outcome::NeedsInput => {
+                um.service(outcome)?;
+                continue;
+            }
+            _ => {}
+        }
+
+        profile.op_counts[op as usize] += 1;
+        profile.programs[profile.current].counts[finger] += 1;
+        if let Some(stacks) = &mut profile.stacks {
+            stacks.tick();
+            if op == 12 && source == 0 {
+                stacks.jump(finger as u32, target, linked);
+            }
+        }
+        if um.service(outcome)?.is_some() {
+            return Ok(());
+        }
+        if replaces_program {
+            profile.begin(Some(source), um.cycles(), um.array(0).unwrap_or(&[]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::IoConsole;
+    use crate::platter::{make_orthography, make_platter};
+
+    /// Counts down a loop three times, then loads a new program that halts.
+    fn program() -> Vec<u32> {
+        vec![
+            make_orthography(1, 3),
+            make_platter(6, 3, 0, 0),      // r3 = -1
+            make_orthography(4, 3),
+            make_platter(3, 1, 1, 3),      // loop: r1 -= 1
+            make_orthography(5, 7),
+            make_platter(0, 5, 4, 1),
+            make_platter(12, 0, 0, 5),     // to loop while r1 != 0
+            make_orthography(6, 2),
+            make_platter(8, 0, 7, 6),
+            make_orthography(1, 0x700000),
+            make_orthography(2, 256),
+            make_platter(4, 1, 1, 2),      // r1 = halt
+            make_orthography(2, 1),
+            make_platter(2, 7, 2, 1),
+            make_orthography(3, 0),
+            make_platter(12, 0, 7, 3),
+        ]
+    }
+
+    fn profiled() -> Profile {
+        let mut um = UM::with_console(program(), IoConsole::new(&b""[..], vec![]));
+        let mut profile = Profile::new();
+        run_profiled(&mut um, &mut profile).unwrap();
+        profile
+    }
+
+    #[test]
+    fn test_counts_per_program() {
+        let profile = profiled();
+        assert_eq!(26, profile.cycles());
+        assert_eq!(4, profile.op_counts[12]);
+        assert_eq!(2, profile.programs.len());
+
+        let first = &profile.programs[0];
+        assert_eq!((None, 0), (first.source, first.loaded_at));
+        assert_eq!(program(), first.platters);
+        assert_eq!(vec![1, 1, 1, 3, 3, 3, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1], first.counts);
+
+        let second = &profile.programs[1];
+        assert_eq!((Some(1), 24, 1), (second.source, second.loaded_at, second.loads));
+        assert_eq!(vec![0, 0x7000_0000], second.platters);
+        assert_eq!(vec![1, 1], second.counts);
+    }
+
+    #[test]
+    fn test_reloaded_program() {
+        let mut profile = Profile::new();
+        profile.begin(None, 0, &[1, 2]);
+        profile.begin(Some(1), 5, &[3]);
+        profile.begin(Some(2), 9, &[1, 2]);
+        assert_eq!(2, profile.programs.len());
+        assert_eq!(0, profile.current);
+        assert_eq!((None, 0, 2), (profile.programs[0].source, profile.programs[0].loaded_at, profile.programs[0].loads));
+    }
+
+    #[test]
+    fn test_call_stacks() {
+        // Calls a routine that calls another, twice.
+        let program = crate::asm::assemble(
+            "
+                    call outer
+                    call outer
+                    halt
+            outer:  add r4, r5, r0    ; save the link
+                    call inner
+                    add r5, r4, r0
+                    ret
+            inner:  ret
+            ",
+        )
+        .unwrap();
+        let mut um = UM::with_console(program, IoConsole::new(&b""[..], vec![]));
+        let mut profile = Profile::with_call_stacks();
+        run_profiled(&mut um, &mut profile).unwrap();
+
+        let mut out = vec![];
+        profile.stacks.unwrap().write_folded(&mut out).unwrap();
+        let folded = String::from_utf8(out).unwrap();
+        // Calls to labels not yet defined take eight instructions, returns two.
+        let expected = "program0 17\nprogram0;00000011 24\nprogram0;00000011;0000001d 4\n";
+        assert_eq!(expected, folded);
+    }
+
+    #[test]
+    fn test_report() {
+        let mut out = vec![];
+        profiled().report(3, &mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.starts_with("UM profile: 26 instructions\n"));
+        assert!(report.contains("\northo              10  38.46%\n"));
+        assert!(report.contains("\n      1     [1]       1  24\n"));
+        assert!(report.contains("\n      0  00000003            3  11.54%  add r1, r1, r3\n"));
+        assert!(report.ends_with("\n      0  00000005            3  11.54%  cmov r5, r4, r1\n"));
+    }
+}
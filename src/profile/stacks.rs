@@ -0,0 +1,142 @@
+//! Approximate call stacks recovered from jumps.
+//!
+//! The UM has no call instruction. Subroutines are entered with Load Program
+//! from array 0 after the return address, the one following the jump, has
+//! been put in a register. So a jump made while some register holds the
+//! address after it is taken for a call, and a jump to the return address of
+//! a frame on the stack for a return from it. Every other jump stays within
+//! the current routine. Routines are named by their entry address.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+
+/// Deeper stacks are taken as a misreading and stop growing.
+const MAX_DEPTH: usize = 1024;
+
+const ROOT: usize = usize::MAX;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Node {
+    parent: usize,
+    /// Entry address, or the program number for roots.
+    entry: u32,
+    count: u64,
+}
+
+/// Instruction counts per recovered call stack, kept as a tree.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CallStacks {
+    nodes: Vec<Node>,
+    children: HashMap<(usize, u32), usize>,
+    /// Frames above the root, with their return addresses.
+    frames: Vec<(usize, u32)>,
+    root: usize,
+}
+
+impl CallStacks {
+    pub fn new() -> Self {
+        CallStacks::default()
+    }
+
+    fn child(&mut self, parent: usize, entry: u32) -> usize {
+        if let Some(&node) = self.children.get(&(parent, entry)) {
+            return node;
+        }
+        self.nodes.push(Node { parent, entry, count: 0 });
+        let node = self.nodes.len() - 1;
+        self.children.insert((parent, entry), node);
+        node
+    }
+
+    fn current(&self) -> usize {
+        self.frames.last().map_or(self.root, |&(node, _)| node)
+    }
+
+    /// Starts over at the bottom of the stack of a newly loaded program.
+    pub fn load(&mut self, program: u32) {
+        self.root = self.child(ROOT, program);
+        self.frames.clear();
+    }
+
+    /// Accounts for a jump within array 0 from `from` to `to`; `linked` says
+    /// whether a register held the return address `from + 1`.
+    pub fn jump(&mut self, from: u32, to: u32, linked: bool) {
+        if let Some(depth) = self.frames.iter().rposition(|&(_, ret)| ret == to) {
+            self.frames.truncate(depth);
+        } else if linked && self.frames.len() < MAX_DEPTH {
+            let node = self.child(self.current(), to);
+            self.frames.push((node, from.wrapping_add(1)));
+        }
+    }
+
+    /// Counts one instruction against the current stack.
+    #[inline]
+    pub fn tick(&mut self) {
+        let node = self.current();
+        if let Some(node) = self.nodes.get_mut(node) {
+            node.count += 1;
+        }
+    }
+
+    fn name(&self, node: usize) -> String {
+        let n = &self.nodes[node];
+        match n.parent {
+            ROOT => format!("program{}", n.entry),
+            _ => format!("{:08x}", n.entry),
+        }
+    }
+
+    /// Writes one line per stack in the folded format of `flamegraph.pl`
+    /// and `inferno`: frames from the root, separated by `;`, then the count.
+    pub fn write_folded<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        for (i, node) in self.nodes.iter().enumerate() {
+            if node.count == 0 {
+                continue;
+            }
+            let mut path = vec![];
+            let mut n = i;
+            while n != ROOT {
+                path.push(self.name(n));
+                n = self.nodes[n].parent;
+            }
+            path.reverse();
+            writeln!(out, "{} {}", path.join(";"), node.count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn folded(stacks: &CallStacks) -> String {
+        let mut out = vec![];
+        stacks.write_folded(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_calls_and_returns() {
+        let mut stacks = CallStacks::new();
+        stacks.load(0);
+        stacks.tick();
+        stacks.jump(10, 100, true); // call 100
+        stacks.tick();
+        stacks.jump(105, 200, true); // call 200
+        stacks.tick();
+        stacks.tick();
+        stacks.jump(210, 220, false); // jump within 200
+        stacks.tick();
+        stacks.jump(230, 11, false); // return from both
+        stacks.tick();
+        stacks.jump(20, 100, true); // call 100 again
+        stacks.tick();
+        assert_eq!("program0 2\nprogram0;00000064 2\nprogram0;00000064;000000c8 3\n", folded(&stacks));
+
+        stacks.load(1);
+        stacks.tick();
+        assert!(folded(&stacks).ends_with("\nprogram1 1\n"));
+    }
+}
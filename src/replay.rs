@@ -0,0 +1,324 @@
+//! Input recording and deterministic replay.
+//!
+//! Input is the machine's only source of nondeterminism, so a log of the
+//! bytes the Input operator consumed, with the cycles at which it did, is
+//! enough to rerun a session exactly. The log also holds the output, which
+//! `replay` compares against to find where a rerun departs from the
+//! original.
+//!
+//! A recording starts with `MAGIC` and a version byte, followed by events of
+//! ten bytes each: a tag (`I` for input, `E` for end of input, `O` for
+//! output), the cycle the operator ran at as a big-endian `u64`, and the
+//! byte, zero for `E`.
+
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+
+use crate::console::Console;
+use crate::fault::Error;
+use crate::um::{Outcome, UM};
+
+pub const MAGIC: &[u8; 5] = b"UMREC";
+pub const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Input consumed a byte, or end of input when `byte` is `None`.
+    Input { cycle: u64, byte: Option<u8> },
+    Output { cycle: u64, byte: u8 },
+    /// Input was requested where the recording has none. Only reported in
+    /// a `Divergence`, never recorded.
+    WantedInput { cycle: u64 },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Input { cycle, byte: Some(b) } => write!(f, "input {:?} at cycle {}", *b as char, cycle),
+            Event::Input { cycle, byte: None } => write!(f, "end of input at cycle {}", cycle),
+            Event::Output { cycle, byte } => write!(f, "output {:?} at cycle {}", *byte as char, cycle),
+            Event::WantedInput { cycle } => write!(f, "request input at cycle {}", cycle),
+        }
+    }
+}
+
+/// The first point at which a replay differs from its recording.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Number of events that matched before this one.
+    pub index: usize,
+    /// The recorded event, `None` past the end of the recording.
+    pub expected: Option<Event>,
+    /// What the machine did instead, `None` if it halted.
+    pub actual: Option<Event>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let describe = |event: &Option<Event>, end: &str| match event {
+            Some(event) => event.to_string(),
+            None => end.to_string(),
+        };
+        write!(
+            f,
+            "replay diverged after {} events: recorded {}, but the machine did {}",
+            self.index,
+            describe(&self.expected, "nothing more"),
+            describe(&self.actual, "halt"),
+        )
+    }
+}
+
+pub struct RecordingWriter<W> {
+    out: W,
+}
+
+impl<W: Write> RecordingWriter<W> {
+    /// Writes the header and returns a writer for the events.
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+        Ok(RecordingWriter { out })
+    }
+
+    pub fn record(&mut self, event: Event) -> io::Result<()> {
+        let (tag, cycle, byte) = match event {
+            Event::Input { cycle, byte: Some(b) } => (b'I', cycle, b),
+            Event::Input { cycle, byte: None } => (b'E', cycle, 0),
+            Event::Output { cycle, byte } => (b'O', cycle, byte),
+            Event::WantedInput { .. } => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "input requests are not recorded"));
+            }
+        };
+        let mut buf = [0; 10];
+        buf[0] = tag;
+        buf[1..9].copy_from_slice(&cycle.to_be_bytes());
+        buf[9] = byte;
+        self.out.write_all(&buf)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+pub struct RecordingReader<R> {
+    input: R,
+}
+
+impl<R: Read> RecordingReader<R> {
+    /// Checks the header and returns a reader for the events.
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut header = [0; 6];
+        input.read_exact(&mut header)?;
+        if &header[..5] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a UM recording"));
+        }
+        if header[5] != VERSION {
+            let message = format!("unsupported recording version {}", header[5]);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        Ok(RecordingReader { input })
+    }
+
+    fn read_event(&mut self) -> io::Result<Option<Event>> {
+        let mut buf = [0; 10];
+        let n = self.input.read(&mut buf[..1])?;
+        if n == 0 {
+            return Ok(None);
+        }
+        self.input.read_exact(&mut buf[1..])?;
+        let cycle = u64::from_be_bytes(buf[1..9].try_into().unwrap());
+        match buf[0] {
+            b'I' => Ok(Some(Event::Input { cycle, byte: Some(buf[9]) })),
+            b'E' => Ok(Some(Event::Input { cycle, byte: None })),
+            b'O' => Ok(Some(Event::Output { cycle, byte: buf[9] })),
+            tag => Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad event tag {:#04x}", tag))),
+        }
+    }
+}
+
+impl<R: Read> Iterator for RecordingReader<R> {
+    type Item = io::Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_event().transpose()
+    }
+}
+
+/// Runs `um` to a halt, recording its input and output.
+///
+/// The recording is flushed whenever the machine waits for input or stops.
+pub fn run_recorded<C: Console, W: Write>(um: &mut UM<C>, recording: &mut RecordingWriter<W>) -> Result<(), Error> {
+    let result = record(um, recording);
+    um.console_mut().flush()?;
+    recording.flush()?;
+    result
+}
+
+fn record<C: Console, W: Write>(um: &mut UM<C>, recording: &mut RecordingWriter<W>) -> Result<(), Error> {
+    loop {
+        let outcome = um.run_until(um.until_flush());
+        match outcome {
+            Outcome::Output(byte) => {
+                // The cycle count already includes the Output operator.
+                recording.record(Event::Output { cycle: um.cycles() - 1, byte })?;
+            }
+            Outcome::NeedsInput => {
+                recording.flush()?;
+                let byte = um.read_input()?;
+                recording.record(Event::Input { cycle: um.cycles(), byte })?;
+                um.answer_input(byte);
+                continue;
+            }
+            _ => {}
+        }
+        match um.service(outcome)? {
+            Some(Outcome::Fault(fault)) => return Err(fault.into()),
+            Some(_) => return Ok(()),
+            None => {}
+        }
+    }
+}
+
+/// Runs `um` on the input in `recording`, checking that it produces the
+/// recorded output at the recorded cycles. Output still goes to the console.
+///
+/// Returns the first divergence, if there is one; the machine stops there.
+pub fn replay<C, I>(um: &mut UM<C>, recording: I) -> Result<Option<Divergence>, Error>
+where
+    C: Console,
+    I: IntoIterator<Item = io::Result<Event>>,
+{
+    let result = check(um, recording.into_iter());
+    um.console_mut().flush()?;
+    result
+}
+
+fn check<C, I>(um: &mut UM<C>, mut recording: I) -> Result<Option<Divergence>, Error>
+where
+    C: Console,
+    I: Iterator<Item = io::Result<Event>>,
+{
+    let mut index = 0;
+    let mut next = recording.next().transpose()?;
+    loop {
+        let actual = match um.run_until(um.until_flush()) {
+            Outcome::Halted => None,
+            Outcome::Output(byte) => Some(Event::Output { cycle: um.cycles() - 1, byte }),
+            Outcome::NeedsInput => match next {
+                Some(Event::Input { cycle, byte }) if cycle == um.cycles() => {
+                    um.answer_input(byte);
+                    um.flush_if_due()?;
+                    next = recording.next().transpose()?;
+                    index += 1;
+                    continue;
+                }
+                // Input at a different cycle, or none recorded.
+                _ => Some(Event::WantedInput { cycle: um.cycles() }),
+            },
+            Outcome::Fault(fault) => return Err(fault.into()),
+            outcome => {
+                um.service(outcome)?;
+                continue;
+            }
+        };
+
+        if actual.is_none() && next.is_none() {
+            return Ok(None);
+        }
+        if actual.is_some() && actual == next {
+            if let Some(Event::Output { byte, .. }) = actual {
+                um.service(Outcome::Output(byte))?;
+            }
+            next = recording.next().transpose()?;
+            index += 1;
+            continue;
+        }
+        return Ok(Some(Divergence { index, expected: next, actual }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::IoConsole;
+    use crate::platter::{make_orthography, make_platter};
+
+    /// Echoes input up to end of input, then prints `!`.
+    fn echo() -> Vec<u32> {
+        vec![
+            make_orthography(2, 7),
+            make_orthography(5, 2),
+            make_orthography(1, 9),        // loop
+            make_platter(11, 0, 0, 3),
+            make_platter(6, 4, 3, 3),      // r4 = !r3, zero at end of input
+            make_platter(0, 1, 2, 4),      // echo unless at end of input
+            make_platter(12, 0, 0, 1),
+            make_platter(10, 0, 0, 3),     // echo
+            make_platter(12, 0, 0, 5),
+            make_orthography(3, '!' as u32),
+            make_platter(10, 0, 0, 3),
+            make_platter(7, 0, 0, 0),
+        ]
+    }
+
+    fn recorded(program: Vec<u32>, input: &[u8]) -> Vec<u8> {
+        let mut um = UM::with_console(program, IoConsole::new(input, vec![]));
+        let mut recording = RecordingWriter::new(vec![]).unwrap();
+        run_recorded(&mut um, &mut recording).unwrap();
+        recording.into_inner()
+    }
+
+    fn replayed(program: Vec<u32>, recording: &[u8]) -> (Option<Divergence>, Vec<u8>) {
+        let mut um = UM::with_console(program, IoConsole::new(&b""[..], vec![]));
+        let events = RecordingReader::new(recording).unwrap();
+        let divergence = replay(&mut um, events).unwrap();
+        (divergence, um.into_console().into_inner().1)
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let recording = recorded(echo(), b"hi");
+        let events: Vec<Event> = RecordingReader::new(recording.as_slice()).unwrap().map(Result::unwrap).collect();
+        assert_eq!(6, events.len());
+        assert_eq!(Event::Input { cycle: 3, byte: Some(b'h') }, events[0]);
+        assert_eq!(Event::Input { cycle: 17, byte: None }, events[4]);
+
+        // The replay reads no console input, only the recording.
+        let (divergence, output) = replayed(echo(), &recording);
+        assert_eq!(None, divergence);
+        assert_eq!(b"hi!", output.as_slice());
+    }
+
+    #[test]
+    fn test_divergences() {
+        let recording = recorded(echo(), b"hi");
+
+        let mut changed = echo();
+        changed[9] = make_orthography(3, '?' as u32);
+        let (divergence, output) = replayed(changed, &recording);
+        let divergence = divergence.unwrap();
+        assert_eq!(5, divergence.index);
+        assert_eq!(Some(Event::Output { cycle: 22, byte: b'?' }), divergence.actual);
+        assert_eq!(b"hi", output.as_slice());
+        assert_eq!(
+            "replay diverged after 5 events: recorded output '!' at cycle 22, but the machine did output '?' at cycle 22",
+            divergence.to_string()
+        );
+
+        let (divergence, _) = replayed(echo(), &recording[..recording.len() - 10]);
+        assert_eq!(None, divergence.unwrap().expected);
+
+        // Without the first input event.
+        let (divergence, _) = replayed(echo(), &[&recording[..6], &recording[16..]].concat());
+        let divergence = divergence.unwrap();
+        assert_eq!(Some(Event::WantedInput { cycle: 3 }), divergence.actual);
+        assert!(divergence.to_string().ends_with(", but the machine did request input at cycle 3"));
+    }
+}
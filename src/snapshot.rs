@@ -0,0 +1,285 @@
+//! Machine snapshots.
+//!
+//! A snapshot file starts with `MAGIC` and a version byte; everything after
+//! that is big-endian:
+//!
+//! * the eight registers and the finger, `u32` each;
+//! * the cycle count, `u64`;
+//! * a pending input flag byte, followed by the `u32` value if it is set;
+//! * the number of array identifiers in use, `u32`, then for each a flag
+//!   byte saying whether it is active and, if it is, its length and
+//!   platters as `u32`s;
+//! * the length of the free list and the identifiers on it, `u32`s.
+//!
+//! The free list is kept in order, so a restored machine hands out the same
+//! identifiers as the original would have.
+
+use std::io;
+use std::io::{Read, Write};
+
+use crate::console::Console;
+use crate::fault::Error;
+use crate::um::{Outcome, UM};
+
+pub const MAGIC: &[u8; 6] = b"UMSNAP";
+pub const VERSION: u8 = 1;
+
+/// Input byte that saves a snapshot in `run_with_hotkey`: Ctrl-].
+pub const HOTKEY: u8 = 0x1d;
+
+/// The complete state of a machine, apart from its console.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub registers: [u32; 8],
+    pub finger: u32,
+    pub cycles: u64,
+    pub pending_input: Option<u32>,
+    /// Arrays by identifier; `None` for identifiers that are free.
+    pub arrays: Vec<Option<Vec<u32>>>,
+    pub freelist: Vec<u32>,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_u8<R: Read>(input: &mut R) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    input.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u32<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    input.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64<R: Read>(input: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    input.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn read_flag<R: Read>(input: &mut R) -> io::Result<bool> {
+    match read_u8(input)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        b => Err(invalid(format!("bad flag byte {}", b))),
+    }
+}
+
+fn read_platters<R: Read>(input: &mut R) -> io::Result<Vec<u32>> {
+    let len = read_u32(input)? as usize;
+    let mut bytes = vec![];
+    input.take(len as u64 * 4).read_to_end(&mut bytes)?;
+    if bytes.len() != len * 4 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes.chunks(4).map(|b| u32::from_be_bytes(b.try_into().unwrap())).collect())
+}
+
+fn write_platters<W: Write>(out: &mut W, platters: &[u32]) -> io::Result<()> {
+    out.write_all(&(platters.len() as u32).to_be_bytes())?;
+    let mut bytes = Vec::with_capacity(platters.len() * 4);
+    for p in platters {
+        bytes.extend(p.to_be_bytes());
+    }
+    out.write_all(&bytes)
+}
+
+impl Snapshot {
+    pub fn write_to<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+        for r in self.registers.iter().chain([&self.finger]) {
+            out.write_all(&r.to_be_bytes())?;
+        }
+        out.write_all(&self.cycles.to_be_bytes())?;
+        match self.pending_input {
+            Some(value) => {
+                out.write_all(&[1])?;
+                out.write_all(&value.to_be_bytes())?;
+            }
+            None => out.write_all(&[0])?,
+        }
+        out.write_all(&(self.arrays.len() as u32).to_be_bytes())?;
+        for array in &self.arrays {
+            match array {
+                Some(platters) => {
+                    out.write_all(&[1])?;
+                    write_platters(out, platters)?;
+                }
+                None => out.write_all(&[0])?,
+            }
+        }
+        write_platters(out, &self.freelist)
+    }
+
+    /// Reads a snapshot, checking that it describes a machine that can run:
+    /// array 0 is active and the free list names each inactive array once.
+    pub fn read_from<R: Read>(input: &mut R) -> io::Result<Self> {
+        let mut header = [0; 7];
+        input.read_exact(&mut header)?;
+        if &header[..6] != MAGIC {
+            return Err(invalid("not a UM snapshot".to_string()));
+        }
+        if header[6] != VERSION {
+            return Err(invalid(format!("unsupported snapshot version {}", header[6])));
+        }
+
+        let mut registers = [0; 8];
+        for r in &mut registers {
+            *r = read_u32(input)?;
+        }
+        let finger = read_u32(input)?;
+        let cycles = read_u64(input)?;
+        let pending_input = match read_flag(input)? {
+            true => Some(read_u32(input)?),
+            false => None,
+        };
+        let count = read_u32(input)?;
+        let mut arrays = vec![];
+        for _ in 0..count {
+            arrays.push(match read_flag(input)? {
+                true => Some(read_platters(input)?),
+                false => None,
+            });
+        }
+        let freelist = read_platters(input)?;
+
+        if !matches!(arrays.first(), Some(Some(_))) {
+            return Err(invalid("array 0 is not active".to_string()));
+        }
+        let mut free = vec![false; arrays.len()];
+        for &id in &freelist {
+            match arrays.get(id as usize) {
+                Some(None) if !free[id as usize] => free[id as usize] = true,
+                _ => return Err(invalid(format!("bad free list entry {}", id))),
+            }
+        }
+        if free.iter().zip(&arrays).any(|(free, array)| !free && array.is_none()) {
+            return Err(invalid("an inactive array is missing from the free list".to_string()));
+        }
+        Ok(Snapshot { registers, finger, cycles, pending_input, arrays, freelist })
+    }
+}
+
+/// Runs `um` to a halt, except that reading `HOTKEY` from the console calls
+/// `save` instead of passing the byte to the program. A line-buffered
+/// terminal only sends it with the Enter that follows, so a newline right
+/// after `HOTKEY` is dropped too.
+///
+/// The machine is waiting on an Input operator at that point, so a restored
+/// snapshot resumes by asking for input again.
+pub fn run_with_hotkey<C, F>(um: &mut UM<C>, mut save: F) -> Result<(), Error>
+where
+    C: Console,
+    F: FnMut(&UM<C>) -> io::Result<()>,
+{
+    let mut saved = false;
+    let result = loop {
+        let outcome = um.run_until(um.until_flush());
+        if outcome == Outcome::NeedsInput {
+            let input = um.read_input()?;
+            match input {
+                Some(HOTKEY) => save(um)?,
+                Some(b'\n') if saved => {}
+                input => um.answer_input(input),
+            }
+            saved = input == Some(HOTKEY);
+            continue;
+        }
+        match um.service(outcome)? {
+            Some(Outcome::Fault(fault)) => break Err(fault.into()),
+            Some(_) => break Ok(()),
+            None => {}
+        }
+    };
+    um.console_mut().flush()?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::IoConsole;
+    use crate::platter::{make_orthography, make_platter};
+
+    /// Allocates two arrays, frees the first, then echoes input.
+    fn program() -> Vec<u32> {
+        vec![
+            make_orthography(1, 3),
+            make_platter(8, 0, 2, 1),
+            make_platter(8, 0, 3, 1),
+            make_platter(9, 0, 0, 2),
+            make_platter(11, 0, 0, 4),
+            make_platter(10, 0, 0, 4),
+            make_platter(11, 0, 0, 4),
+            make_platter(10, 0, 0, 4),
+            make_platter(8, 0, 5, 1),
+            make_platter(7, 0, 0, 0),
+        ]
+    }
+
+    fn round_trip(snapshot: &Snapshot) -> io::Result<Snapshot> {
+        let mut buf = vec![];
+        snapshot.write_to(&mut buf).unwrap();
+        Snapshot::read_from(&mut buf.as_slice())
+    }
+
+    #[test]
+    fn test_hotkey_and_restore() {
+        let mut um = UM::with_console(program(), IoConsole::new(&b"a\x1d\nc"[..], vec![]));
+        let mut saved = None;
+        run_with_hotkey(&mut um, |um| {
+            saved = Some(um.snapshot());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(b"ac", um.console().writer().as_slice());
+
+        let snapshot = round_trip(&saved.unwrap()).unwrap();
+        assert_eq!(6, snapshot.finger);
+        assert_eq!(vec![Some(program()), None, Some(vec![0; 3])], snapshot.arrays);
+        assert_eq!(vec![1], snapshot.freelist);
+
+        let mut restored = UM::restore(snapshot, IoConsole::new(&b"b"[..], vec![]));
+        restored.spin_cycle().unwrap();
+        assert_eq!(b"b", restored.console().writer().as_slice());
+        assert_eq!(1, restored.registers()[5]);
+        assert_eq!(9, restored.cycles());
+    }
+
+    #[test]
+    fn test_hotkey_keeps_later_newlines() {
+        let mut um = UM::with_console(program(), IoConsole::new(&b"\x1d\n\n\x1dc"[..], vec![]));
+        let mut saves = 0;
+        run_with_hotkey(&mut um, |_| {
+            saves += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(2, saves);
+        assert_eq!(b"\nc", um.console().writer().as_slice());
+    }
+
+    #[test]
+    fn test_invalid_snapshots() {
+        let um = UM::with_console(program(), IoConsole::new(&b""[..], vec![]));
+        let mut snapshot = um.snapshot();
+        assert_eq!(snapshot, round_trip(&snapshot).unwrap());
+
+        snapshot.arrays.push(None);
+        assert!(round_trip(&snapshot).is_err());
+        snapshot.freelist.push(1);
+        assert_eq!(snapshot, round_trip(&snapshot).unwrap());
+        snapshot.freelist.push(1);
+        assert!(round_trip(&snapshot).is_err());
+
+        let mut buf = vec![];
+        um.snapshot().write_to(&mut buf).unwrap();
+        buf.truncate(buf.len() - 2);
+        assert!(Snapshot::read_from(&mut buf.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,350 @@
+//! Execution traces.
+//!
+//! A trace file starts with `MAGIC` and a version byte, followed by one
+//! record per executed instruction, in order:
+//!
+//! * the finger, as an unsigned LEB128 number;
+//! * the platter, four bytes big-endian;
+//! * what the instruction wrote, as LEB128 numbers: the new value of
+//!   register A for Conditional Move, Array Index and the arithmetic
+//!   operators, of B for Allocation and of C for Input; the array, offset and
+//!   value for Array Amendment; nothing for the others, including
+//!   Orthography, whose value is in the platter.
+//!
+//! Records have no framing, so a trace can be read while it is written and
+//! a truncated one is readable up to its last complete record. The cycle of
+//! a record is its position in the file.
+
+use std::io;
+use std::io::{BufRead, Write};
+use std::ops::RangeInclusive;
+
+use crate::console::Console;
+use crate::disasm;
+use crate::fault::Error;
+use crate::platter::*;
+use crate::um::{Outcome, UM};
+
+pub const MAGIC: &[u8; 7] = b"UMTRACE";
+pub const VERSION: u8 = 1;
+
+/// The state an instruction changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    None,
+    Register { index: usize, value: u32 },
+    Array { array: u32, offset: u32, value: u32 },
+}
+
+/// One executed instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub cycle: u64,
+    pub finger: u32,
+    pub platter: u32,
+    pub effect: Effect,
+}
+
+/// The register an operator writes, if any. Orthography is left out since
+/// its value is already in the platter.
+fn written_register(p: u32) -> Option<usize> {
+    match op_code(p) {
+        0 | 1 | 3..=6 => Some(rega_offset(p)),
+        8 => Some(regb_offset(p)),
+        11 => Some(regc_offset(p)),
+        _ => None,
+    }
+}
+
+fn write_leb128<W: Write>(out: &mut W, mut value: u32) -> io::Result<()> {
+    let mut buf = [0; 5];
+    let mut n = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[n] = byte;
+            return out.write_all(&buf[..=n]);
+        }
+        buf[n] = byte | 0x80;
+        n += 1;
+    }
+}
+
+/// Reads a LEB128 number; `None` at a clean end of input.
+fn read_leb128<R: BufRead>(input: &mut R) -> io::Result<Option<u32>> {
+    let mut value: u32 = 0;
+    for shift in (0..35).step_by(7) {
+        let mut byte = [0];
+        if input.read(&mut byte)? == 0 {
+            return match shift {
+                0 => Ok(None),
+                _ => Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+        }
+        value |= ((byte[0] & 0x7f) as u32) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "LEB128 number too long"))
+}
+
+fn required<R: BufRead>(input: &mut R) -> io::Result<u32> {
+    read_leb128(input)?.ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+}
+
+pub struct TraceWriter<W> {
+    out: W,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// Writes the header and returns a writer for the records.
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+        Ok(TraceWriter { out })
+    }
+
+    pub fn record(&mut self, finger: u32, platter: u32, effect: Effect) -> io::Result<()> {
+        write_leb128(&mut self.out, finger)?;
+        self.out.write_all(&platter.to_be_bytes())?;
+        match effect {
+            Effect::None => Ok(()),
+            Effect::Register { value, .. } => write_leb128(&mut self.out, value),
+            Effect::Array { array, offset, value } => {
+                write_leb128(&mut self.out, array)?;
+                write_leb128(&mut self.out, offset)?;
+                write_leb128(&mut self.out, value)
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+pub struct TraceReader<R> {
+    input: R,
+    cycle: u64,
+}
+
+impl<R: BufRead> TraceReader<R> {
+    /// Checks the header and returns a reader for the records.
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut header = [0; 8];
+        input.read_exact(&mut header)?;
+        if &header[..7] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a UM trace"));
+        }
+        if header[7] != VERSION {
+            let message = format!("unsupported trace version {}", header[7]);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+        }
+        Ok(TraceReader { input, cycle: 0 })
+    }
+
+    fn read_event(&mut self) -> io::Result<Option<Event>> {
+        let Some(finger) = read_leb128(&mut self.input)? else {
+            return Ok(None);
+        };
+        let mut platter = [0; 4];
+        self.input.read_exact(&mut platter)?;
+        let platter = u32::from_be_bytes(platter);
+        let effect = match (op_code(platter), written_register(platter)) {
+            (2, _) => Effect::Array {
+                array: required(&mut self.input)?,
+                offset: required(&mut self.input)?,
+                value: required(&mut self.input)?,
+            },
+            (_, Some(index)) => Effect::Register { index, value: required(&mut self.input)? },
+            _ => Effect::None,
+        };
+        let event = Event { cycle: self.cycle, finger, platter, effect };
+        self.cycle += 1;
+        Ok(Some(event))
+    }
+}
+
+impl<R: BufRead> Iterator for TraceReader<R> {
+    type Item = io::Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_event().transpose()
+    }
+}
+
+/// Runs `um` to a halt, recording every executed instruction.
+///
+/// Faulting instructions are not recorded, since they change nothing. The
+/// trace is flushed whenever the machine waits for input or stops.
+pub fn run_traced<C: Console, W: Write>(um: &mut UM<C>, trace: &mut TraceWriter<W>) -> Result<(), Error> {
+    let result = record(um, trace);
+    um.console_mut().flush()?;
+    trace.flush()?;
+    result
+}
+
+fn record<C: Console, W: Write>(um: &mut UM<C>, trace: &mut TraceWriter<W>) -> Result<(), Error> {
+    loop {
+        let finger = um.finger();
+        let platter = um.array(0).and_then(|a| a.get(finger)).copied().unwrap_or(0);
+        let r = *um.registers();
+        let amendment = Effect::Array {
+            array: r[rega_offset(platter)],
+            offset: r[regb_offset(platter)],
+            value: r[regc_offset(platter)],
+        };
+
+        let outcome = um.step();
+        let effect = match (op_code(platter), written_register(platter)) {
+            (2, _) => amendment,
+            (_, Some(index)) => Effect::Register { index, value: um.registers()[index] },
+            _ => Effect::None,
+        };
+        if outcome == Outcome::NeedsInput {
+            trace.flush()?;
+            um.service(outcome)?;
+            continue;
+        }
+        match um.service(outcome)? {
+            Some(Outcome::Fault(fault)) => return Err(fault.into()),
+            Some(_) => {
+                trace.record(finger as u32, platter, effect)?;
+                return Ok(());
+            }
+            None => trace.record(finger as u32, platter, effect)?,
+        }
+    }
+}
+
+/// Which events `print` shows; an empty filter shows everything.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub cycles: Option<RangeInclusive<u64>>,
+    pub fingers: Option<RangeInclusive<u32>>,
+    pub op_codes: Vec<u8>,
+}
+
+impl Filter {
+    pub fn matches(&self, event: &Event) -> bool {
+        self.cycles.as_ref().is_none_or(|r| r.contains(&event.cycle))
+            && self.fingers.as_ref().is_none_or(|r| r.contains(&event.finger))
+            && (self.op_codes.is_empty() || self.op_codes.contains(&op_code(event.platter)))
+    }
+}
+
+/// Formats one event as a disassembly line with its effect as a comment.
+pub fn render(event: &Event) -> String {
+    let text = disasm::render(event.platter);
+    let effect = match event.effect {
+        Effect::None => return format!("{:>10} {:08x}: {}", event.cycle, event.finger, text),
+        Effect::Register { index, value } => format!("r{} = {:#010x}", index, value),
+        Effect::Array { array, offset, value } => format!("[{}][{:#x}] = {:#010x}", array, offset, value),
+    };
+    // Lines whose text already has a comment get the effect appended to it.
+    let text = match text.contains(';') {
+        true => format!("{}, {}", text, effect),
+        false => format!("{:<24}; {}", text, effect),
+    };
+    format!("{:>10} {:08x}: {}", event.cycle, event.finger, text)
+}
+
+/// Prints the events of a trace that match `filter`.
+pub fn print<R: BufRead, W: Write>(trace: TraceReader<R>, filter: &Filter, out: &mut W) -> io::Result<()> {
+    let cycles_end = filter.cycles.as_ref().map(|r| *r.end());
+    for event in trace {
+        let event = event?;
+        if cycles_end.is_some_and(|end| event.cycle > end) {
+            break;
+        }
+        if filter.matches(&event) {
+            writeln!(out, "{}", render(&event))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::IoConsole;
+    use crate::platter::{make_orthography, make_platter};
+
+    fn program() -> Vec<u32> {
+        vec![
+            make_orthography(1, 2),
+            make_platter(8, 0, 2, 1),
+            make_orthography(3, 0x1234),
+            make_platter(2, 2, 0, 3),
+            make_platter(11, 0, 0, 4),
+            make_platter(7, 0, 0, 0),
+        ]
+    }
+
+    fn traced(program: Vec<u32>, input: &[u8]) -> (Result<(), Error>, Vec<u8>) {
+        let mut um = UM::with_console(program, IoConsole::new(input, vec![]));
+        let mut trace = TraceWriter::new(vec![]).unwrap();
+        let result = run_traced(&mut um, &mut trace);
+        (result, trace.into_inner())
+    }
+
+    fn events(trace: &[u8]) -> Vec<Event> {
+        TraceReader::new(trace).unwrap().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn test_leb128() {
+        for value in [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, u32::MAX] {
+            let mut buf = vec![];
+            write_leb128(&mut buf, value).unwrap();
+            assert_eq!(Some(value), read_leb128(&mut buf.as_slice()).unwrap());
+        }
+        assert!(read_leb128(&mut [0x80].as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_records() {
+        let (result, trace) = traced(program(), b"x");
+        result.unwrap();
+        let events = events(&trace);
+        assert_eq!(6, events.len());
+        assert_eq!(Event { cycle: 0, finger: 0, platter: program()[0], effect: Effect::None }, events[0]);
+        assert_eq!(Effect::Register { index: 2, value: 1 }, events[1].effect);
+        assert_eq!(Effect::Array { array: 1, offset: 0, value: 0x1234 }, events[3].effect);
+        assert_eq!(Effect::Register { index: 4, value: b'x' as u32 }, events[4].effect);
+
+        // A truncated trace is readable up to its last complete record.
+        let mut reader = TraceReader::new(&trace[..trace.len() - 3]).unwrap();
+        assert_eq!(5, reader.by_ref().take_while(Result::is_ok).count());
+    }
+
+    #[test]
+    fn test_faults_are_not_recorded() {
+        let (result, trace) = traced(vec![make_orthography(1, 1), make_platter(5, 1, 1, 2)], b"");
+        assert!(matches!(result, Err(Error::Fault(_))));
+        assert_eq!(1, events(&trace).len());
+    }
+
+    #[test]
+    fn test_print_filters() {
+        let (_, trace) = traced(program(), b"");
+        let filter = Filter { op_codes: vec![13], ..Filter::default() };
+        let mut out = vec![];
+        print(TraceReader::new(trace.as_slice()).unwrap(), &filter, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(2, text.lines().count());
+        assert!(text.ends_with("ortho r3, 0x1234\n"));
+        assert!(text.starts_with("         0 00000000: ortho r1, 0x2\n"));
+
+        let filter = Filter { fingers: Some(1..=1), ..Filter::default() };
+        let mut out = vec![];
+        print(TraceReader::new(trace.as_slice()).unwrap(), &filter, &mut out).unwrap();
+        assert_eq!("         1 00000001: alloc r2, r1            ; r2 = 0x00000001\n", String::from_utf8(out).unwrap());
+    }
+}
@@ -0,0 +1,277 @@
+//! Ahead-of-time translation of a UM image to Rust source.
+//!
+//! The generated program embeds the image and a `blocks` function holding
+//! each run of register operators (Conditional Move, Addition,
+//! Multiplication, Division, Nand, Orthography) as straight-line Rust. It is
+//! built against this crate, so every other operator is still executed by the
+//! interpreter. So is a run after an Amendment of array 0 overlapping it,
+//! and everything while Load Program has replaced the image with another
+//! program:
+//!
+//! ```text
+//! icfp2006-rust translate sandmark.umz -o examples/sandmark.rs
+//! cargo run --release --example sandmark
+//! ```
+
+use std::collections::BTreeSet;
+use std::io;
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::array::Array;
+use crate::code::{decode_all, Op};
+
+/// Runs the block starting at the given finger over the registers, provided
+/// it fits in the budget, and returns how many instructions it executed.
+/// Zero means there is no block there.
+pub type Blocks = fn(usize, &mut [u32; 8], u64) -> usize;
+
+/// Blocks attached to a machine, with the image they were generated from.
+#[derive(Debug)]
+pub(crate) struct Attached {
+    blocks: Blocks,
+    image: Array,
+    code: Arc<Vec<Op>>,
+    /// Whether array 0 holds `image`, apart from amendments.
+    loaded: bool,
+    /// Per address, whether a block starting there may still run.
+    valid: Vec<bool>,
+}
+
+impl Attached {
+    pub fn new(blocks: Blocks, image: Array, code: Arc<Vec<Op>>) -> Self {
+        let valid = vec![true; code.len()];
+        Attached { blocks, image, code, loaded: true, valid }
+    }
+
+    /// Disables the blocks overlapping `offset` after array 0 was amended
+    /// there. A block runs to the end of its run of register operators, so
+    /// these are the ones starting in the same run, up to `offset`.
+    pub fn invalidate(&mut self, offset: usize) {
+        if !self.loaded {
+            return;
+        }
+        for start in (0..=offset).rev() {
+            if !self.code.get(start).is_some_and(Op::is_register_op) {
+                break;
+            }
+            self.valid[start] = false;
+        }
+    }
+
+    /// Notes that Load Program replaced array 0 with `program`; the blocks
+    /// apply again, all of them, if it is the original image.
+    pub fn load(&mut self, program: &Array) {
+        self.loaded = self.image.same_platters(program);
+        if self.loaded {
+            self.valid.fill(true);
+        }
+    }
+
+    #[inline]
+    pub fn run(&self, finger: usize, registers: &mut [u32; 8], budget: u64) -> usize {
+        match self.loaded && self.valid.get(finger) == Some(&true) {
+            true => (self.blocks)(finger, registers, budget),
+            false => 0,
+        }
+    }
+}
+
+/// Addresses a block may start at: the start of each run of register
+/// operators, and any address inside a run that an Orthography immediate
+/// could be jumping to.
+fn entries(code: &[Op]) -> BTreeSet<usize> {
+    let mut entries = BTreeSet::new();
+    for (i, op) in code.iter().enumerate() {
+        if !op.is_register_op() {
+            continue;
+        }
+        if i == 0 || !code[i - 1].is_register_op() {
+            entries.insert(i);
+        }
+        if op.code == 13 {
+            let target = op.value as usize;
+            if code.get(target).is_some_and(Op::is_register_op) {
+                entries.insert(target);
+            }
+        }
+    }
+    entries
+}
+
+fn write_block<W: Write>(out: &mut W, ops: &[Op]) -> io::Result<()> {
+    writeln!(out, "            if budget < {} {{ return 0; }}", ops.len())?;
+    for (i, op) in ops.iter().enumerate() {
+        let (a, b, c) = (op.a, op.b, op.c);
+        match op.code {
+            0 => writeln!(out, "            if r[{}] != 0 {{ r[{}] = r[{}]; }}", c, a, b)?,
+            3 => writeln!(out, "            r[{}] = r[{}].wrapping_add(r[{}]);", a, b, c)?,
+            4 => writeln!(out, "            r[{}] = r[{}].wrapping_mul(r[{}]);", a, b, c)?,
+            5 => {
+                writeln!(out, "            if r[{}] == 0 {{ return {}; }}", c, i)?;
+                writeln!(out, "            r[{}] = r[{}] / r[{}];", a, b, c)?;
+            }
+            6 => writeln!(out, "            r[{}] = !(r[{}] & r[{}]);", a, b, c)?,
+            13 => writeln!(out, "            r[{}] = {:#x};", a, op.value)?,
+            _ => unreachable!("not a register operator"),
+        }
+    }
+    writeln!(out, "            {}", ops.len())
+}
+
+/// Writes a Rust program equivalent to running `image`.
+pub fn translate<W: Write>(image: &[u32], out: &mut W) -> io::Result<()> {
+    let code = decode_all(image);
+
+    writeln!(out, "// Generated by `icfp2006-rust translate`.")?;
+    writeln!(out)?;
+    writeln!(out, "use std::process;")?;
+    writeln!(out)?;
+    writeln!(out, "use icfp2006_rust::{{Error, UM}};")?;
+    writeln!(out)?;
+    writeln!(out, "static IMAGE: [u32; {}] = [", image.len())?;
+    for chunk in image.chunks(8) {
+        let words: Vec<String> = chunk.iter().map(|p| format!("{:#010x},", p)).collect();
+        writeln!(out, "    {}", words.join(" "))?;
+    }
+    writeln!(out, "];")?;
+    writeln!(out)?;
+
+    let mut arms = vec![];
+    for start in entries(&code) {
+        let len = code[start..].iter().take_while(|op| op.is_register_op()).count();
+        if len < 2 {
+            continue;
+        }
+        writeln!(arms, "        {} => {{", start)?;
+        write_block(&mut arms, &code[start..start + len])?;
+        writeln!(arms, "        }}")?;
+    }
+    writeln!(out, "#[allow(clippy::all, unreachable_code)]")?;
+    if arms.is_empty() {
+        writeln!(out, "fn blocks(_finger: usize, _r: &mut [u32; 8], _budget: u64) -> usize {{")?;
+        writeln!(out, "    0")?;
+    } else {
+        writeln!(out, "fn blocks(finger: usize, r: &mut [u32; 8], budget: u64) -> usize {{")?;
+        writeln!(out, "    match finger {{")?;
+        out.write_all(&arms)?;
+        writeln!(out, "        _ => 0,")?;
+        writeln!(out, "    }}")?;
+    }
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+
+    out.write_all(
+        br#"fn main() {
+    let mut um = UM::new(IMAGE.to_vec());
+    um.attach_blocks(blocks);
+
+    match um.spin_cycle() {
+        Ok(()) => {}
+        Err(e @ Error::Fault(_)) => {
+            eprintln!("\nUM: {}", e);
+            process::exit(2);
+        }
+        Err(e) => {
+            eprintln!("\nUM: {}", e);
+            process::exit(1);
+        }
+    }
+}
+"#,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platter::{make_orthography, make_platter};
+    use crate::{Outcome, UM};
+
+    #[test]
+    fn test_entries() {
+        let code = decode_all(&[
+            make_orthography(1, 3),
+            make_orthography(2, 4),
+            make_platter(10, 0, 0, 1),
+            make_platter(3, 0, 1, 2),
+            make_platter(6, 0, 1, 2),
+        ]);
+        assert_eq!(vec![0, 3, 4], entries(&code).into_iter().collect::<Vec<_>>());
+    }
+
+    /// `examples/translated.rs` is built with the crate, so this checks that
+    /// what `translate` emits compiles.
+    #[test]
+    fn test_translated_example() {
+        let image = [
+            make_orthography(1, 6),
+            make_platter(3, 0, 1, 1),
+            make_orthography(2, 60),
+            make_platter(3, 0, 0, 2),
+            make_orthography(3, 10),
+            make_platter(10, 0, 0, 0),
+            make_platter(10, 0, 0, 3),
+            make_platter(7, 0, 0, 0),
+        ];
+        let mut out = vec![];
+        translate(&image, &mut out).unwrap();
+        assert_eq!(include_str!("../examples/translated.rs"), String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn test_translated_without_blocks() {
+        let mut out = vec![];
+        translate(&[make_platter(7, 0, 0, 0)], &mut out).unwrap();
+        let source = String::from_utf8(out).unwrap();
+        assert!(source.contains("fn blocks(_finger: usize, _r: &mut [u32; 8], _budget: u64) -> usize {\n    0\n}\n"));
+    }
+
+    /// Hand-written equivalent of the generated code for the program below.
+    fn blocks(finger: usize, r: &mut [u32; 8], budget: u64) -> usize {
+        match finger {
+            0 => {
+                if budget < 2 { return 0; }
+                r[1] = 0x6;
+                r[0] = r[1].wrapping_add(r[1]);
+                2
+            }
+            _ => 0,
+        }
+    }
+
+    #[test]
+    fn test_attached_blocks() {
+        let program = vec![
+            make_orthography(1, 6),
+            make_platter(3, 0, 1, 1),
+            make_platter(7, 0, 0, 0),
+        ];
+        let mut um = UM::new(program);
+        um.attach_blocks(blocks);
+        assert_eq!(Outcome::BudgetExhausted, um.run_until(2));
+        assert_eq!(12, um.registers()[0]);
+        assert_eq!(2, um.cycles());
+        assert_eq!(Outcome::Halted, um.run_until(2));
+    }
+
+    #[test]
+    fn test_invalidated_blocks() {
+        let image = vec![make_orthography(1, 6), make_platter(3, 0, 1, 1), make_platter(7, 0, 0, 0)];
+        let code = Arc::new(decode_all(&image));
+        let mut attached = Attached::new(blocks, Array::new(image.clone()), code);
+        let mut r = [0; 8];
+        attached.invalidate(2);
+        assert_eq!(2, attached.run(0, &mut r, 2));
+
+        attached.invalidate(1);
+        assert_eq!(0, attached.run(0, &mut r, 2));
+
+        // Loading anything else disables the blocks; the image re-enables them.
+        attached.load(&Array::new(image[1..].to_vec()));
+        attached.load(&Array::new(image.clone()));
+        assert_eq!(2, attached.run(0, &mut r, 2));
+        attached.load(&Array::default());
+        assert_eq!(0, attached.run(0, &mut r, 2));
+    }
+}
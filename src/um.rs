@@ -0,0 +1,750 @@
+use std::io;
+use std::sync::Arc;
+
+use crate::array::Array;
+use crate::code::{decode_all, Op};
+use crate::console::{Console, StdConsole};
+use crate::fault::{Error, Fault, FaultKind};
+use crate::snapshot::Snapshot;
+use crate::translate::{Attached, Blocks};
+
+/// Why `UM::step` or `UM::run_until` returned control to the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The instruction completed and the machine can keep going.
+    Running,
+    Halted,
+    /// The finger rests on an Input operator; see `UM::provide_input`.
+    NeedsInput,
+    /// An Output operator emitted a byte.
+    Output(u8),
+    Fault(Fault),
+    /// `run_until` executed its whole budget.
+    BudgetExhausted,
+}
+
+#[derive(Debug)]
+pub struct UM<C = StdConsole> {
+    registers: [u32; 8],
+    programs: Vec<Option<Array>>,
+    code: Arc<Vec<Op>>,
+    finger: usize,
+    freelist: Vec<u32>,
+    cycles: u64,
+    pending_input: Option<u32>,
+    flush_interval: u64,
+    /// Cycle count when the console was last flushed.
+    last_flush: u64,
+    program_generation: u64,
+    blocks: Option<Attached>,
+    #[cfg(feature = "jit")]
+    jit: Option<Box<crate::jit::Jit>>,
+    console: C,
+}
+
+/// Default number of cycles between console flushes in `UM::spin_cycle`.
+pub const FLUSH_INTERVAL: u64 = 1 << 24;
+
+impl UM {
+    /// Creates a machine with `program` loaded as array 0, attached to stdin and stdout.
+    pub fn new(program: Vec<u32>) -> Self {
+        UM::with_console(program, StdConsole::default())
+    }
+}
+
+impl<C: Console> UM<C> {
+    /// Creates a machine with `program` loaded as array 0, attached to `console`.
+    pub fn with_console(program: Vec<u32>, console: C) -> Self {
+        let program = Array::new(program);
+        UM {
+            registers: [0; 8],
+            code: Arc::new(decode_all(program.platters())),
+            programs: vec![Some(program)],
+            finger: 0,
+            freelist: vec![],
+            cycles: 0,
+            pending_input: None,
+            flush_interval: FLUSH_INTERVAL,
+            last_flush: 0,
+            program_generation: 0,
+            blocks: None,
+            #[cfg(feature = "jit")]
+            jit: None,
+            console,
+        }
+    }
+
+    /// Creates a machine in the state captured by `snapshot`, attached to `console`.
+    pub fn restore(snapshot: Snapshot, console: C) -> Self {
+        let programs: Vec<Option<Array>> =
+            snapshot.arrays.into_iter().map(|a| a.map(Array::new)).collect();
+        let code = match programs.first() {
+            Some(Some(program)) => decode_all(program.platters()),
+            _ => vec![],
+        };
+        UM {
+            registers: snapshot.registers,
+            code: Arc::new(code),
+            programs,
+            finger: snapshot.finger as usize,
+            freelist: snapshot.freelist,
+            cycles: snapshot.cycles,
+            pending_input: snapshot.pending_input,
+            last_flush: snapshot.cycles,
+            ..UM::with_console(vec![], console)
+        }
+    }
+
+    /// Captures everything about the machine except its console.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers,
+            finger: self.finger as u32,
+            cycles: self.cycles,
+            pending_input: self.pending_input,
+            arrays: self.programs.iter().map(|a| a.as_ref().map(|a| a.platters().to_vec())).collect(),
+            freelist: self.freelist.clone(),
+        }
+    }
+
+    pub fn console(&self) -> &C {
+        &self.console
+    }
+
+    pub fn console_mut(&mut self) -> &mut C {
+        &mut self.console
+    }
+
+    pub fn into_console(self) -> C {
+        self.console
+    }
+
+    pub fn registers(&self) -> &[u32; 8] {
+        &self.registers
+    }
+
+    pub fn registers_mut(&mut self) -> &mut [u32; 8] {
+        &mut self.registers
+    }
+
+    pub fn finger(&self) -> usize {
+        self.finger
+    }
+
+    pub fn set_finger(&mut self, finger: usize) {
+        self.finger = finger;
+    }
+
+    /// Returns the array with the given identifier, if it is active.
+    pub fn array(&self, id: u32) -> Option<&[u32]> {
+        self.programs.get(id as usize)?.as_ref().map(|a| a.platters())
+    }
+
+    /// Writes `value` into an array, returning `false` if the offset is not mapped.
+    pub fn amend(&mut self, id: u32, offset: u32, value: u32) -> bool {
+        self.amend_platter(id, offset, value).is_ok()
+    }
+
+    /// Number of instructions executed so far.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Supplies the byte for an Input operator that returned `Outcome::NeedsInput`.
+    pub fn provide_input(&mut self, byte: u8) {
+        self.pending_input = Some(byte as u32);
+    }
+
+    /// Signals end of input to a waiting Input operator, which loads all ones.
+    pub fn end_input(&mut self) {
+        self.pending_input = Some(u32::MAX);
+    }
+
+    /// Sets how many cycles `spin_cycle` and `service` may run without
+    /// flushing the console.
+    ///
+    /// The console is always flushed when the machine waits for input or stops.
+    pub fn set_flush_interval(&mut self, cycles: u64) {
+        self.flush_interval = cycles.max(1);
+    }
+
+    pub fn flush_interval(&self) -> u64 {
+        self.flush_interval
+    }
+
+    /// Runs until the machine halts, servicing I/O through the console.
+    pub fn spin_cycle(&mut self) -> Result<(), Error> {
+        let result = self.service_console();
+        self.console.flush()?;
+        result
+    }
+
+    fn service_console(&mut self) -> Result<(), Error> {
+        loop {
+            let outcome = self.run_until(self.until_flush());
+            match self.service(outcome)? {
+                Some(Outcome::Fault(fault)) => return Err(fault.into()),
+                Some(_) => return Ok(()),
+                None => {}
+            }
+        }
+    }
+
+    /// A budget for `run_until` that ends at the next interval flush. Output
+    /// ends `run_until` early, so the interval is counted from the last
+    /// flush rather than from each call.
+    pub fn until_flush(&self) -> u64 {
+        let due = self.last_flush.saturating_add(self.flush_interval);
+        due.saturating_sub(self.cycles).max(1)
+    }
+
+    /// Acts on `outcome` as `spin_cycle` does: output goes to the console,
+    /// input is read from it, and the console is flushed once the flush
+    /// interval has passed. Returns the outcome if it stops the machine,
+    /// which is a halt or a fault.
+    ///
+    /// Drivers handle the outcomes they observe themselves and pass on the
+    /// rest.
+    pub fn service(&mut self, outcome: Outcome) -> io::Result<Option<Outcome>> {
+        match outcome {
+            Outcome::Halted | Outcome::Fault(_) => return Ok(Some(outcome)),
+            Outcome::Output(byte) => self.console.output(byte)?,
+            Outcome::NeedsInput => {
+                let input = self.read_input()?;
+                self.answer_input(input);
+            }
+            Outcome::BudgetExhausted | Outcome::Running => {}
+        }
+        self.flush_if_due()?;
+        Ok(None)
+    }
+
+    /// Reads input from the console for a waiting Input operator, without
+    /// answering it.
+    pub fn read_input(&mut self) -> io::Result<Option<u8>> {
+        // The console flushes before it blocks.
+        let input = self.console.input()?;
+        self.last_flush = self.cycles;
+        Ok(input)
+    }
+
+    /// Supplies a byte to a waiting Input operator, or end of input for `None`.
+    pub fn answer_input(&mut self, input: Option<u8>) {
+        match input {
+            Some(byte) => self.provide_input(byte),
+            None => self.end_input(),
+        }
+    }
+
+    /// Flushes the console if `flush_interval` cycles have passed since it
+    /// was last flushed.
+    pub fn flush_if_due(&mut self) -> io::Result<()> {
+        if self.cycles.saturating_sub(self.last_flush) >= self.flush_interval {
+            self.console.flush()?;
+            self.last_flush = self.cycles;
+        }
+        Ok(())
+    }
+
+    /// Compiles hot basic blocks in array 0 to native code.
+    ///
+    /// Observable behaviour, including cycle counts, is unchanged.
+    #[cfg(feature = "jit")]
+    pub fn enable_jit(&mut self) -> Result<(), String> {
+        self.jit = Some(Box::new(crate::jit::Jit::new(self.code.len())?));
+        Ok(())
+    }
+
+    /// Runs `blocks` in place of the interpreter wherever it applies: not
+    /// over code that has been amended since, nor while Load Program has
+    /// replaced array 0 with anything but its current contents.
+    ///
+    /// `blocks` must have been generated by `translate` from the current
+    /// contents of array 0.
+    pub fn attach_blocks(&mut self, blocks: Blocks) {
+        let image = self.programs[0].as_ref().map(Array::share_platters).unwrap_or_default();
+        self.blocks = Some(Attached::new(blocks, image, self.code.clone()));
+    }
+
+    /// Changes whenever array 0 is replaced by Load Program or amended.
+    pub fn program_generation(&self) -> u64 {
+        self.program_generation
+    }
+
+    /// Executes at most `budget` instructions, stopping early at the first
+    /// outcome the host has to act on.
+    pub fn run_until(&mut self, budget: u64) -> Outcome {
+        if self.blocks.is_some() {
+            return self.run_accelerated(budget, Self::enter_blocks);
+        }
+        #[cfg(feature = "jit")]
+        if self.jit.is_some() {
+            return self.run_accelerated(budget, Self::enter_jit);
+        }
+        self.interpret(budget)
+    }
+
+    /// Alternates between `enter`, which may run a straight-line block of
+    /// operators that neither branch, allocate nor do I/O and returns how
+    /// many it ran, and single interpreted steps.
+    fn run_accelerated(&mut self, budget: u64, enter: fn(&mut Self, u64) -> usize) -> Outcome {
+        let mut remaining = budget;
+        while remaining > 0 {
+            let n = enter(self, remaining);
+            if n > 0 {
+                self.finger += n;
+                self.cycles += n as u64;
+                remaining -= n as u64;
+                if remaining == 0 {
+                    break;
+                }
+            }
+            match self.step() {
+                Outcome::Running => remaining -= 1,
+                outcome => return outcome,
+            }
+        }
+        Outcome::BudgetExhausted
+    }
+
+    fn enter_blocks(&mut self, budget: u64) -> usize {
+        match &self.blocks {
+            Some(blocks) => blocks.run(self.finger, &mut self.registers, budget),
+            None => 0,
+        }
+    }
+
+    #[cfg(feature = "jit")]
+    fn enter_jit(&mut self, budget: u64) -> usize {
+        match &mut self.jit {
+            Some(jit) => {
+                jit.run(self.finger, &self.code, &mut self.registers, &mut self.programs, budget).unwrap_or(0)
+            }
+            None => 0,
+        }
+    }
+
+    /// Executes a single instruction.
+    ///
+    /// An Input operator without pending input leaves the finger in place and
+    /// returns `Outcome::NeedsInput`; the instruction is retried by the next step.
+    #[inline(always)]
+    pub fn step(&mut self) -> Outcome {
+        match self.interpret(1) {
+            Outcome::BudgetExhausted => Outcome::Running,
+            outcome => outcome,
+        }
+    }
+
+    /// The dispatch loop behind `run_until` and `step`. It only builds an
+    /// `Outcome` when it has to stop.
+    #[inline(always)]
+    fn interpret(&mut self, budget: u64) -> Outcome {
+        // The finger and the instructions executed live in locals and are
+        // written back whenever the loop stops or calls out.
+        let mut finger = self.finger;
+        let mut executed = 0;
+        // `self.code` only changes in `amend_platter` and `load_program`,
+        // after which this is taken again.
+        let mut code: *const [Op] = &**self.code;
+
+        macro_rules! stop {
+            ($outcome:expr) => {{
+                self.finger = finger;
+                self.cycles += executed;
+                return $outcome;
+            }}
+        }
+
+        macro_rules! reg {
+            ($x:ident) => {
+                self.registers[$x]
+            }
+        }
+
+        macro_rules! check {
+            ($e:expr) => {
+                match $e {
+                    Ok(v) => v,
+                    Err(kind) => stop!(self.fault(kind)),
+                }
+            }
+        }
+
+        while executed < budget {
+            // SAFETY: `code` points into `self.code`, which is kept alive
+            // and unchanged until `code` is refreshed.
+            let op = match unsafe { &*code }.get(finger) {
+                Some(&op) => op,
+                None => stop!(self.fault(FaultKind::FingerOutOfBounds)),
+            };
+            // Decoded fields are below 8; the masks spare the bounds checks.
+            let a = op.a as usize & 7;
+            let b = op.b as usize & 7;
+            let c = op.c as usize & 7;
+
+            match op.code {
+                // Conditional Move
+                0 => if reg!(c) != 0 { reg!(a) = reg!(b) },
+
+                // Array Index
+                1 => reg!(a) = check!(self.platter(reg!(b), reg!(c))),
+
+                // Array Amendment
+                2 => {
+                    check!(self.amend_platter(reg!(a), reg!(b), reg!(c)));
+                    code = &**self.code;
+                }
+
+                // Addition
+                3 => reg!(a) = reg!(b).wrapping_add(reg!(c)),
+
+                // Multiplication
+                4 => reg!(a) = reg!(b).wrapping_mul(reg!(c)),
+                // Division
+                5 => match reg!(b).checked_div(reg!(c)) {
+                    Some(v) => reg!(a) = v,
+                    None => stop!(self.fault(FaultKind::DivisionByZero)),
+                },
+                // Nand
+                6 => reg!(a) = !(reg!(b) & reg!(c)),
+                // Halt
+                7 => stop!(Outcome::Halted),
+                // Allocation
+                8 => {
+                    let array = Some(Array::new(vec![0; reg!(c) as usize]));
+                    if let Some(i) = self.freelist.pop() {
+                        reg!(b) = i;
+                        self.programs[i as usize] = array;
+                    } else {
+                        reg!(b) = self.programs.len() as u32;
+                        self.programs.push(array);
+                    }
+                },
+                // Abandonment
+                9 => {
+                    let id = reg!(c);
+                    if id == 0 {
+                        stop!(self.fault(FaultKind::AbandonedProgram));
+                    }
+                    check!(self.active(id));
+                    self.programs[id as usize] = None;
+                    self.freelist.push(id);
+                },
+                // Output
+                10 => match u8::try_from(reg!(c)) {
+                    Ok(byte) => {
+                        finger += 1;
+                        executed += 1;
+                        stop!(Outcome::Output(byte));
+                    }
+                    Err(_) => stop!(self.fault(FaultKind::OutputOutOfRange(reg!(c)))),
+                },
+                // Input
+                11 => match self.pending_input.take() {
+                    Some(value) => reg!(c) = value,
+                    None => stop!(Outcome::NeedsInput),
+                },
+                // Load Program
+                12 => {
+                    if reg!(b) != 0 {
+                        check!(self.load_program(reg!(b)));
+                        code = &**self.code;
+                    }
+                    finger = reg!(c) as usize;
+                    executed += 1;
+                    continue;
+                },
+                // Orthography
+                13 => reg!(a) = op.value,
+                op => stop!(self.fault(FaultKind::InvalidOperator(op))),
+            }
+            finger += 1;
+            executed += 1;
+        }
+        stop!(Outcome::BudgetExhausted)
+    }
+
+    /// Replaces array 0 with a copy of array `id`.
+    #[cold]
+    fn load_program(&mut self, id: u32) -> Result<(), FaultKind> {
+        let array = self.active_mut(id)?;
+        let code = array.code();
+        let platters = array.share_platters();
+        if let Some(blocks) = &mut self.blocks {
+            blocks.load(&platters);
+        }
+        self.code = code;
+        self.programs[0] = Some(platters);
+        self.program_generation += 1;
+        #[cfg(feature = "jit")]
+        if let Some(jit) = &mut self.jit {
+            jit.reset(self.code.len());
+        }
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn active(&self, id: u32) -> Result<&Array, FaultKind> {
+        match self.programs.get(id as usize) {
+            Some(Some(array)) => Ok(array),
+            _ => Err(FaultKind::InactiveArray(id)),
+        }
+    }
+
+    #[inline(always)]
+    fn active_mut(&mut self, id: u32) -> Result<&mut Array, FaultKind> {
+        match self.programs.get_mut(id as usize) {
+            Some(Some(array)) => Ok(array),
+            _ => Err(FaultKind::InactiveArray(id)),
+        }
+    }
+
+    #[inline(always)]
+    fn platter(&self, id: u32, offset: u32) -> Result<u32, FaultKind> {
+        self.active(id)?
+            .platters()
+            .get(offset as usize)
+            .copied()
+            .ok_or(FaultKind::OffsetOutOfBounds { array: id, offset })
+    }
+
+    #[inline(always)]
+    fn amend_platter(&mut self, id: u32, offset: u32, value: u32) -> Result<(), FaultKind> {
+        self.active_mut(id)?
+            .amend(offset as usize, value)
+            .ok_or(FaultKind::OffsetOutOfBounds { array: id, offset })?;
+        if id == 0 {
+            Arc::make_mut(&mut self.code)[offset as usize] = Op::decode(value);
+            self.program_generation += 1;
+            if let Some(blocks) = &mut self.blocks {
+                blocks.invalidate(offset as usize);
+            }
+            #[cfg(feature = "jit")]
+            if let Some(jit) = &mut self.jit {
+                jit.invalidate(offset as usize);
+            }
+        }
+        Ok(())
+    }
+
+    #[cold]
+    fn fault(&self, kind: FaultKind) -> Outcome {
+        let platter = self.array(0).and_then(|a| a.get(self.finger)).copied();
+        Outcome::Fault(Fault {
+            finger: self.finger,
+            platter: platter.unwrap_or(0),
+            registers: self.registers,
+            kind,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platter::{make_orthography, make_platter};
+    use crate::console::IoConsole;
+
+    #[test]
+    fn test_arithmetic_and_halt() {
+        let mut um = UM::new(vec![
+            make_orthography(1, 6),
+            make_orthography(2, 7),
+            make_platter(4, 0, 1, 2),
+            make_platter(7, 0, 0, 0),
+        ]);
+        um.spin_cycle().unwrap();
+        assert_eq!(42, um.registers()[0]);
+        assert_eq!(3, um.finger());
+    }
+
+    #[test]
+    fn test_echo_through_memory_console() {
+        let program = vec![
+            make_platter(11, 0, 0, 1),
+            make_platter(10, 0, 0, 1),
+            make_platter(7, 0, 0, 0),
+        ];
+        let mut um = UM::with_console(program, IoConsole::new(&b"x"[..], vec![]));
+        um.spin_cycle().unwrap();
+        assert_eq!(b"x", um.console().writer().as_slice());
+    }
+
+    /// Counts output bytes and flushes.
+    #[derive(Default)]
+    struct CountingConsole {
+        outputs: usize,
+        flushes: usize,
+    }
+
+    impl Console for CountingConsole {
+        fn output(&mut self, _: u8) -> std::io::Result<()> {
+            self.outputs += 1;
+            Ok(())
+        }
+
+        fn input(&mut self) -> std::io::Result<Option<u8>> {
+            Ok(None)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    /// Prints a dot every five cycles, 2000 times.
+    fn dots() -> Vec<u32> {
+        vec![
+            make_orthography(1, '.' as u32),
+            make_orthography(2, 2000),
+            make_orthography(3, 5),
+            make_orthography(4, 10),
+            make_platter(6, 5, 0, 0),      // r5 = -1
+            make_platter(10, 0, 0, 1),     // loop
+            make_platter(3, 2, 2, 5),
+            make_platter(3, 6, 4, 0),
+            make_platter(0, 6, 3, 2),
+            make_platter(12, 0, 0, 6),
+            make_platter(7, 0, 0, 0),
+        ]
+    }
+
+    #[test]
+    fn test_flush_interval_spans_output() {
+        let mut um = UM::with_console(dots(), CountingConsole::default());
+        um.set_flush_interval(1000);
+        um.spin_cycle().unwrap();
+        assert_eq!(10005, um.cycles());
+        assert_eq!(2000, um.console().outputs);
+        // Once every 1000 cycles, and at the halt.
+        assert_eq!(11, um.console().flushes);
+    }
+
+    #[test]
+    fn test_service_step_by_step() {
+        let mut um = UM::with_console(dots(), CountingConsole::default());
+        um.set_flush_interval(1000);
+        loop {
+            let outcome = um.step();
+            if let Some(outcome) = um.service(outcome).unwrap() {
+                assert_eq!(Outcome::Halted, outcome);
+                break;
+            }
+        }
+        assert_eq!(2000, um.console().outputs);
+        assert_eq!(10, um.console().flushes);
+    }
+
+    #[test]
+    fn test_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<UM<IoConsole<&[u8], Vec<u8>>>>();
+    }
+
+    #[test]
+    fn test_cooperative_io() {
+        let program = vec![
+            make_platter(11, 0, 0, 1),
+            make_platter(10, 0, 0, 1),
+            make_platter(7, 0, 0, 0),
+        ];
+        let mut um = UM::new(program);
+        assert_eq!(Outcome::NeedsInput, um.run_until(10));
+        assert_eq!(Outcome::NeedsInput, um.run_until(10));
+        um.provide_input(b'!');
+        assert_eq!(Outcome::Output(b'!'), um.run_until(10));
+        assert_eq!(Outcome::BudgetExhausted, um.run_until(0));
+        assert_eq!(Outcome::Halted, um.run_until(10));
+        assert_eq!(2, um.cycles());
+    }
+
+    #[test]
+    fn test_invalid_operator_faults() {
+        let mut um = UM::new(vec![0xe000_0000]);
+        match um.step() {
+            Outcome::Fault(fault) => assert_eq!(FaultKind::InvalidOperator(14), fault.kind),
+            outcome => panic!("unexpected {:?}", outcome),
+        }
+    }
+
+    fn fault_of(program: Vec<u32>) -> Fault {
+        let mut um = UM::new(program);
+        match um.run_until(100) {
+            Outcome::Fault(fault) => fault,
+            outcome => panic!("unexpected {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn test_faults() {
+        let fault = fault_of(vec![make_orthography(1, 3), make_platter(5, 0, 1, 2)]);
+        assert_eq!(FaultKind::DivisionByZero, fault.kind);
+        assert_eq!(1, fault.finger);
+        assert_eq!(3, fault.registers[1]);
+
+        let fault = fault_of(vec![make_orthography(1, 5), make_platter(1, 0, 1, 2)]);
+        assert_eq!(FaultKind::InactiveArray(5), fault.kind);
+
+        let fault = fault_of(vec![make_orthography(2, 9), make_platter(1, 0, 1, 2)]);
+        assert_eq!(FaultKind::OffsetOutOfBounds { array: 0, offset: 9 }, fault.kind);
+
+        let fault = fault_of(vec![make_platter(9, 0, 0, 0)]);
+        assert_eq!(FaultKind::AbandonedProgram, fault.kind);
+
+        let fault = fault_of(vec![
+            make_orthography(1, 4),
+            make_platter(8, 0, 2, 1),
+            make_platter(9, 0, 0, 2),
+            make_platter(9, 0, 0, 2),
+        ]);
+        assert_eq!(FaultKind::InactiveArray(1), fault.kind);
+
+        let fault = fault_of(vec![make_orthography(1, 0)]);
+        assert_eq!(FaultKind::FingerOutOfBounds, fault.kind);
+    }
+
+    #[test]
+    fn test_end_of_input() {
+        let program = vec![make_platter(11, 0, 0, 1), make_platter(7, 0, 0, 0)];
+        let mut um = UM::with_console(program, IoConsole::new(&b""[..], vec![]));
+        um.spin_cycle().unwrap();
+        assert_eq!(0xffff_ffff, um.registers()[1]);
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        let halt = make_platter(7, 0, 0, 0);
+        let mut um = UM::new(vec![
+            make_orthography(1, 5),
+            make_orthography(2, halt >> 8),
+            make_orthography(3, 256),
+            make_platter(4, 2, 2, 3),
+            make_platter(2, 0, 1, 2),
+            0xe000_0000,
+        ]);
+        assert_eq!(Outcome::Halted, um.run_until(10));
+        assert_eq!(Some(halt), um.array(0).map(|a| a[5]));
+    }
+
+    #[test]
+    fn test_load_program_shares_until_amended() {
+        let mut um = UM::new(vec![
+            make_orthography(1, 2),
+            make_platter(8, 0, 2, 1),
+            make_platter(12, 0, 2, 3),
+        ]);
+        assert_eq!(Outcome::Running, um.step());
+        assert_eq!(Outcome::Running, um.step());
+        let id = um.registers()[2];
+        assert!(um.amend(id, 1, make_platter(7, 0, 0, 0)));
+        assert_eq!(Outcome::Running, um.step());
+
+        assert!(um.amend(0, 0, 0xdead));
+        assert_eq!(Some(&[0xdead, 0x7000_0000][..]), um.array(0));
+        assert_eq!(Some(&[0, 0x7000_0000][..]), um.array(id));
+        assert_eq!(Outcome::Running, um.step());
+        assert_eq!(Outcome::Halted, um.step());
+    }
+}